@@ -10,7 +10,7 @@ use bevy::ecs::system::{ResMut, Single};
 use bevy::image::Image;
 use bevy::input::mouse::MouseWheel;
 use bevy::math::Vec2;
-use bevy::prelude::{Color, FixedUpdate, Gizmos, Update, Vec3};
+use bevy::prelude::{Color, FixedUpdate, Update, Vec3};
 use bevy::render::texture::ImagePlugin;
 use bevy::sprite::Sprite;
 use bevy::state::app::AppExtStates;
@@ -42,6 +42,7 @@ use bevy_salva2d::plugin::{
     SalvaPhysicsPlugin,
 };
 use bevy_salva2d::rapier_integration::RapierColliderSampling;
+use bevy_salva2d::render::{FluidDebugColor, SalvaDebugRenderPlugin};
 use bevy_salva2d::salva::{math::Real, solver::ArtificialViscosity};
 use nalgebra::Vector2;
 
@@ -79,6 +80,7 @@ fn main() {
                 },
             )
             .in_schedule(FixedUpdate),
+        SalvaDebugRenderPlugin::default(),
     ));
     app.init_state::<AppState>()
         .insert_resource(GameAsset::default())
@@ -345,6 +347,7 @@ fn startup(mut commands: Commands, game_assets: Res<GameAsset>, image_assets: Re
     let _fluid = commands
         .spawn((
             FluidPositions(positions),
+            FluidDebugColor(Color::linear_rgb(0.1843, 0.5647, 0.7686)),
             AppendNonPressureForces(vec![Box::new(ArtificialViscosity::new(0.0, 0.0))]),
         ))
         .id();
@@ -352,32 +355,14 @@ fn startup(mut commands: Commands, game_assets: Res<GameAsset>, image_assets: Re
 
 pub fn update(
     mut commands: Commands,
-    fluid_q: Query<(Entity, &FluidPositions), With<SalvaFluidHandle>>,
-    salva_context: Query<&SalvaContext>,
+    fluid_q: Query<Entity, With<SalvaFluidHandle>>,
     keys: Res<ButtonInput<KeyCode>>,
-    mut gizmos: Gizmos,
 ) {
     let result = fluid_q.get_single();
     if result.is_err() {
         return;
     }
-    let (fluid_entity, positions) = result.unwrap();
-
-    //draw particles
-    // Get the SalvaContext resource
-    let salva_context = match salva_context.get_single() {
-        Ok(ctx) => ctx,
-        Err(_) => return,
-    };
-
-    // Draw particles
-    for pos in positions.0.iter() {
-        gizmos.circle_2d(
-            Vec2::new(pos.x, pos.y),
-            salva_context.liquid_world.particle_radius(),
-            Color::linear_rgb(0.1843, 0.5647, 0.7686),
-        );
-    }
+    let fluid_entity = result.unwrap();
 
     //nonpressure force testing
     if keys.just_pressed(KeyCode::KeyG) {
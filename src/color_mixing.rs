@@ -0,0 +1,286 @@
+use crate::fluid::SalvaFluidHandle;
+use crate::math::Real;
+use crate::particle_attributes::FluidParticleColors;
+use crate::plugin::{SalvaContextEntityLink, WriteSalvaContext};
+use bevy::prelude::*;
+use salva::geometry::ParticlesContacts;
+use salva::object::{BoundarySet, Fluid};
+use salva::solver::NonPressureForce;
+use salva::TimestepManager;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+
+/// The colors shared between a fluid entity and the [`ColorMixing`] force of its salva fluid.
+#[derive(Default)]
+struct ColorMixingState {
+    colors: Vec<LinearRgba>,
+    rate: Real,
+    /// The colors mixed during the current substep. They are committed once every fluid of the
+    /// context was mixed, so that all fluids mix from the same colors.
+    mixed: Option<Vec<LinearRgba>>,
+}
+
+impl ColorMixingState {
+    fn commit(&mut self) {
+        if let Some(mixed) = self.mixed.take() {
+            self.colors = mixed;
+        }
+    }
+}
+
+type SharedColors = Arc<RwLock<ColorMixingState>>;
+
+/// The colors of the fluids of a salva context, keyed by the index of the fluid in the
+/// context's fluid order, which is how salva's contacts refer to fluids.
+type ColorRegistry = Arc<RwLock<HashMap<usize, SharedColors>>>;
+
+/// A non-pressure force blending the color of each particle with the colors of its neighbors,
+/// weighted by the SPH kernel.
+///
+/// This runs inside the salva step and reuses its neighborhood search. It is attached
+/// automatically to fluids with [`FluidParticleColors`] once their
+/// [`diffusion_rate`](crate::particle_attributes::FluidParticleAttributes::diffusion_rate) is
+/// positive, and is appended after the fluid's other non-pressure forces.
+///
+/// Particles mix with the particles of every fluid of the context that has colors, e.g. to mix
+/// two paints. Each fluid mixes at its own rate.
+pub struct ColorMixing {
+    colors: SharedColors,
+    registry: ColorRegistry,
+}
+
+impl NonPressureForce for ColorMixing {
+    fn solve(
+        &mut self,
+        timestep: &TimestepManager,
+        _kernel_radius: Real,
+        fluid_fluid_contacts: &ParticlesContacts,
+        _fluid_boundaries_contacts: &ParticlesContacts,
+        fluid: &mut Fluid,
+        _boundaries: &BoundarySet,
+        _densities: &[Real],
+    ) {
+        let registry = self.registry.read().unwrap();
+        // If this fluid was already mixed, a new substep started: the fluids mixed during the
+        // previous one are all done.
+        if self.colors.read().unwrap().mixed.is_some() {
+            for colors in registry.values() {
+                colors.write().unwrap().commit();
+            }
+            self.colors.write().unwrap().commit();
+        }
+
+        let mixed = {
+            let own = self.colors.read().unwrap();
+            if own.rate <= 0.0 || own.colors.len() != fluid.num_particles() {
+                return;
+            }
+            let others: HashMap<usize, RwLockReadGuard<ColorMixingState>> = registry
+                .iter()
+                .filter(|(_, colors)| !Arc::ptr_eq(colors, &self.colors))
+                .map(|(model, colors)| (*model, colors.read().unwrap()))
+                .collect();
+
+            let blend = (own.rate * timestep.dt()).min(1.0);
+            (0..own.colors.len())
+                .map(|i| {
+                    let contacts = fluid_fluid_contacts.particle_contacts(i).read().unwrap();
+                    // Particle volumes are uniform within a context, so the kernel weight is
+                    // enough once normalized.
+                    let neighbors = contacts.iter().filter_map(|c| {
+                        let color = if c.j_model == c.i_model {
+                            own.colors.get(c.j)
+                        } else {
+                            others.get(&c.j_model)?.colors.get(c.j)
+                        };
+                        Some((*color?, c.weight))
+                    });
+                    mix(own.colors[i], neighbors, blend)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        self.colors.write().unwrap().mixed = Some(mixed);
+    }
+
+    fn apply_permutation(&mut self, permutation: &[usize]) {
+        let mut state = self.colors.write().unwrap();
+        state.commit();
+        if state.colors.len() != permutation.len() {
+            return;
+        }
+        let colors: Vec<_> = permutation.iter().map(|i| state.colors[*i]).collect();
+        state.colors = colors;
+    }
+}
+
+/// Blends `color` towards the weighted average of its `neighbors` colors by `blend`.
+fn mix(color: LinearRgba, neighbors: impl Iterator<Item = (LinearRgba, Real)>, blend: Real) -> LinearRgba {
+    let mut total_weight = 0.0;
+    let mut change = LinearRgba::NONE;
+    for (neighbor, weight) in neighbors {
+        total_weight += weight;
+        change += (neighbor - color) * weight;
+    }
+    if total_weight > 0.0 {
+        color + change * (blend / total_weight)
+    } else {
+        color
+    }
+}
+
+/// Links a fluid entity to the [`ColorMixing`] force of its salva fluid.
+#[derive(Component)]
+pub struct FluidColorMixing(SharedColors);
+
+/// Attaches a [`ColorMixing`] force to the fluids whose colors diffuse, and hands it their
+/// current colors and diffusion rate and the colors of the other fluids of their context.
+pub fn sync_fluid_color_mixing(
+    mut commands: Commands,
+    mut context_writer: WriteSalvaContext,
+    fluids: Query<(
+        Entity,
+        &SalvaFluidHandle,
+        &SalvaContextEntityLink,
+        &FluidParticleColors,
+        Option<&FluidColorMixing>,
+    )>,
+    orphans: Query<&FluidColorMixing, Without<FluidParticleColors>>,
+    mut registries: Local<HashMap<Entity, ColorRegistry>>,
+) {
+    let mut registered: HashMap<Entity, HashMap<usize, SharedColors>> = HashMap::new();
+
+    for (entity, handle, link, colors, mixing) in fluids.iter() {
+        let shared = match mixing {
+            Some(mixing) => {
+                let mut state = mixing.0.write().unwrap();
+                state.colors.clone_from(&colors.values);
+                state.rate = colors.diffusion_rate;
+                state.mixed = None;
+                mixing.0.clone()
+            }
+            None if colors.diffusion_rate > 0.0 => {
+                // The context or the fluid may be gone, e.g. while the fluid is being removed.
+                let Some(mut context) = context_writer.try_context(link) else {
+                    continue;
+                };
+                let context = &mut *context;
+                let Some(fluid) = context.liquid_world.fluids_mut().get_mut(handle.0) else {
+                    continue;
+                };
+                let shared = Arc::new(RwLock::new(ColorMixingState {
+                    colors: colors.values.clone(),
+                    rate: colors.diffusion_rate,
+                    mixed: None,
+                }));
+                fluid.nonpressure_forces.push(Box::new(ColorMixing {
+                    colors: shared.clone(),
+                    registry: registries.entry(link.0).or_default().clone(),
+                }));
+                context
+                    .nonpressure_force_configs
+                    .entry(handle.0)
+                    .or_default()
+                    .push(None);
+                commands.entity(entity).insert(FluidColorMixing(shared.clone()));
+                shared
+            }
+            None => continue,
+        };
+
+        let Some(context) = context_writer.try_context(link) else {
+            continue;
+        };
+        let Some(model) = context
+            .liquid_world
+            .fluids()
+            .iter()
+            .position(|(fluid_handle, _)| fluid_handle == handle.0)
+        else {
+            continue;
+        };
+        registered.entry(link.0).or_default().insert(model, shared);
+    }
+
+    for (context_entity, registry) in registries.iter() {
+        *registry.write().unwrap() = registered.remove(context_entity).unwrap_or_default();
+    }
+
+    // Colors were removed from the fluid, disable its mixing.
+    for mixing in orphans.iter() {
+        mixing.0.write().unwrap().rate = 0.0;
+    }
+}
+
+/// Copies the colors mixed during the simulation step back to [`FluidParticleColors`].
+pub fn writeback_fluid_colors(
+    mut fluids: Query<(&mut FluidParticleColors, &FluidColorMixing)>,
+) {
+    for (mut colors, mixing) in fluids.iter_mut() {
+        let mut state = mixing.0.write().unwrap();
+        state.commit();
+        if state.rate > 0.0 && state.colors.len() == colors.values.len() {
+            colors.values.clone_from(&state.colors);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: LinearRgba = LinearRgba::RED;
+    const BLUE: LinearRgba = LinearRgba::BLUE;
+
+    #[test]
+    fn mixing_moves_towards_the_weighted_neighbor_average() {
+        let neighbors = [(BLUE, 1.0), (RED, 1.0)];
+        assert_eq!(mix(RED, neighbors.into_iter(), 1.0), (RED + BLUE) * 0.5);
+        assert_eq!(mix(RED, neighbors.into_iter(), 0.5), RED * 0.75 + BLUE * 0.25);
+
+        let neighbors = [(BLUE, 3.0), (RED, 1.0)];
+        assert_eq!(mix(RED, neighbors.into_iter(), 1.0), RED * 0.25 + BLUE * 0.75);
+    }
+
+    #[test]
+    fn particles_without_neighbors_keep_their_color() {
+        assert_eq!(mix(RED, std::iter::empty(), 1.0), RED);
+        assert_eq!(mix(RED, [(BLUE, 0.0)].into_iter(), 1.0), RED);
+    }
+
+    #[test]
+    fn permutations_of_another_length_are_ignored() {
+        let colors = Arc::new(RwLock::new(ColorMixingState {
+            colors: vec![RED, BLUE],
+            rate: 1.0,
+            mixed: None,
+        }));
+        let mut mixing = ColorMixing {
+            colors: colors.clone(),
+            registry: ColorRegistry::default(),
+        };
+
+        mixing.apply_permutation(&[0, 1, 2]);
+        assert_eq!(colors.read().unwrap().colors, [RED, BLUE]);
+        mixing.apply_permutation(&[1, 0]);
+        assert_eq!(colors.read().unwrap().colors, [BLUE, RED]);
+    }
+
+    #[test]
+    fn pending_colors_are_committed_before_permuting() {
+        let colors = Arc::new(RwLock::new(ColorMixingState {
+            colors: vec![RED, BLUE],
+            rate: 1.0,
+            mixed: Some(vec![BLUE, BLUE]),
+        }));
+        let mut mixing = ColorMixing {
+            colors: colors.clone(),
+            registry: ColorRegistry::default(),
+        };
+
+        mixing.apply_permutation(&[1, 0]);
+        let state = colors.read().unwrap();
+        assert_eq!(state.colors, [BLUE, BLUE]);
+        assert!(state.mixed.is_none());
+    }
+}
@@ -0,0 +1,156 @@
+use crate::math::Real;
+use crate::plugin::SalvaContext;
+use bevy::diagnostic::{Diagnostic, DiagnosticPath, Diagnostics, DiagnosticsStore};
+use bevy::prelude::*;
+use std::time::Duration;
+
+/// The total number of fluid particles over every salva context.
+pub const PARTICLE_COUNT: DiagnosticPath = DiagnosticPath::const_new("salva/particle_count");
+/// The time spent stepping every salva context, in milliseconds.
+pub const STEP_TIME: DiagnosticPath = DiagnosticPath::const_new("salva/step_time");
+/// The part of [`STEP_TIME`] spent in contexts coupled with Rapier, in milliseconds.
+pub const COUPLED_STEP_TIME: DiagnosticPath = DiagnosticPath::const_new("salva/coupled_step_time");
+/// The number of salva solver steps taken over every salva context.
+///
+/// Salva doesn't expose the iteration count of its pressure solver, so this counts the solver
+/// runs instead: one per substep, more with [`TimestepMode::Interpolated`](crate::plugin::TimestepMode)
+/// catching up.
+pub const SOLVER_STEPS: DiagnosticPath = DiagnosticPath::const_new("salva/solver_steps");
+/// The mean compression of the fluid particles, `max(ρ / ρ0 - 1, 0)`, in percent. Only measured
+/// when [`SalvaDiagnosticsSettings::measure_density_error`] is set.
+pub const DENSITY_ERROR: DiagnosticPath = DiagnosticPath::const_new("salva/density_error");
+/// The total number of boundary samples over every salva context.
+pub const BOUNDARY_SAMPLES: DiagnosticPath = DiagnosticPath::const_new("salva/boundary_samples");
+
+/// The path of the diagnostic with the number of fluid particles of a single salva context.
+/// It is registered when the context is added, and disabled when it is removed.
+pub fn context_particle_count_path(context: Entity) -> DiagnosticPath {
+    DiagnosticPath::new(format!("salva/particle_count/{context}"))
+}
+
+/// Registers the diagnostics of the salva simulation, except the per-context ones.
+pub(crate) fn register_diagnostics(app: &mut App) {
+    use bevy::diagnostic::RegisterDiagnostic;
+
+    app.register_diagnostic(Diagnostic::new(PARTICLE_COUNT))
+        .register_diagnostic(Diagnostic::new(STEP_TIME).with_suffix("ms"))
+        .register_diagnostic(Diagnostic::new(COUPLED_STEP_TIME).with_suffix("ms"))
+        .register_diagnostic(Diagnostic::new(SOLVER_STEPS))
+        .register_diagnostic(Diagnostic::new(DENSITY_ERROR).with_suffix("%"))
+        .register_diagnostic(Diagnostic::new(BOUNDARY_SAMPLES));
+}
+
+/// Settings of the salva diagnostics.
+#[derive(Resource, Reflect, Copy, Clone, Debug, Default)]
+#[reflect(Resource, Default)]
+pub struct SalvaDiagnosticsSettings {
+    /// Whether to measure [`DENSITY_ERROR`]. This estimates the density of every particle after
+    /// each step, which is about as costly as a solver iteration.
+    pub measure_density_error: bool,
+}
+
+/// How the last run of the step systems went for a salva context. Added automatically to salva
+/// contexts.
+#[derive(Component, Reflect, Copy, Clone, Debug, Default)]
+#[reflect(Component, Default)]
+pub struct SalvaStepStats {
+    /// The time spent stepping the context.
+    pub step_time: Duration,
+    /// The number of salva solver steps taken.
+    pub solver_steps: u32,
+    /// The amount of simulated time the context advanced by.
+    pub simulated_time: Real,
+    /// Whether the context was stepped through its coupling with Rapier.
+    pub coupled: bool,
+}
+
+/// Registers the particle count diagnostic of new salva contexts, and disables the one of removed
+/// salva contexts.
+///
+/// [`DiagnosticsStore`] can't remove diagnostics, so the diagnostic of a removed context is
+/// disabled and its history cleared, which hides it from the diagnostic loggers.
+pub fn register_context_diagnostics(
+    new_contexts: Query<Entity, Added<SalvaContext>>,
+    mut removed_contexts: RemovedComponents<SalvaContext>,
+    mut store: ResMut<DiagnosticsStore>,
+) {
+    for entity in removed_contexts.read() {
+        if let Some(diagnostic) = store.get_mut(&context_particle_count_path(entity)) {
+            diagnostic.is_enabled = false;
+            diagnostic.clear_history();
+        }
+    }
+
+    for entity in new_contexts.iter() {
+        let path = context_particle_count_path(entity);
+        match store.get_mut(&path) {
+            Some(diagnostic) => diagnostic.is_enabled = true,
+            None => store.add(Diagnostic::new(path)),
+        }
+    }
+}
+
+/// Measures the salva diagnostics after the simulation steps.
+pub fn update_salva_diagnostics(
+    mut diagnostics: Diagnostics,
+    contexts: Query<(Entity, &SalvaContext, &SalvaStepStats)>,
+    settings: Option<Res<SalvaDiagnosticsSettings>>,
+) {
+    let measure_density_error = settings.is_some_and(|settings| settings.measure_density_error);
+    let mut particle_count = 0;
+    let mut boundary_samples = 0;
+    let mut step_time = Duration::ZERO;
+    let mut coupled_step_time = Duration::ZERO;
+    let mut solver_steps = 0;
+    let mut compression = 0.0;
+    let mut measured_particles = 0;
+
+    for (entity, context, stats) in contexts.iter() {
+        let context_particle_count: usize = context
+            .liquid_world
+            .fluids()
+            .iter()
+            .map(|(_, fluid)| fluid.num_particles())
+            .sum();
+        diagnostics.add_measurement(&context_particle_count_path(entity), || {
+            context_particle_count as f64
+        });
+        particle_count += context_particle_count;
+        boundary_samples += context
+            .liquid_world
+            .boundaries()
+            .iter()
+            .map(|(_, boundary)| boundary.positions.len())
+            .sum::<usize>();
+
+        step_time += stats.step_time;
+        if stats.coupled {
+            coupled_step_time += stats.step_time;
+        }
+        solver_steps += stats.solver_steps;
+
+        // The densities only change when the context is stepped.
+        if measure_density_error && stats.solver_steps > 0 {
+            let fluids = context.liquid_world.fluids();
+            for (handle, densities) in context.compute_densities() {
+                let density0 = fluids.get(handle).unwrap().density0;
+                compression += densities
+                    .iter()
+                    .map(|density| (density / density0 - 1.0).max(0.0))
+                    .sum::<Real>();
+                measured_particles += densities.len();
+            }
+        }
+    }
+
+    diagnostics.add_measurement(&PARTICLE_COUNT, || particle_count as f64);
+    diagnostics.add_measurement(&BOUNDARY_SAMPLES, || boundary_samples as f64);
+    diagnostics.add_measurement(&STEP_TIME, || step_time.as_secs_f64() * 1000.0);
+    diagnostics.add_measurement(&COUPLED_STEP_TIME, || coupled_step_time.as_secs_f64() * 1000.0);
+    diagnostics.add_measurement(&SOLVER_STEPS, || solver_steps as f64);
+    if measured_particles > 0 {
+        diagnostics.add_measurement(&DENSITY_ERROR, || {
+            compression as f64 / measured_particles as f64 * 100.0
+        });
+    }
+}
@@ -0,0 +1,300 @@
+use crate::diagnostics::SalvaStepStats;
+use crate::math::{Real, Vect};
+use crate::plugin::SalvaContext;
+use bevy::prelude::*;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+/// A file format fluid particles can be exported to.
+#[derive(Reflect, Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ParticleExportFormat {
+    /// ASCII PLY point cloud.
+    #[default]
+    Ply,
+    /// ASCII legacy VTK poly data, e.g. for ParaView.
+    Vtk,
+    /// Comma-separated values, with a header row.
+    Csv,
+}
+
+impl ParticleExportFormat {
+    /// The file extension of this format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Ply => "ply",
+            Self::Vtk => "vtk",
+            Self::Csv => "csv",
+        }
+    }
+}
+
+/// A particle as exported by [`SalvaContext::export_particles`].
+struct ExportedParticle {
+    /// The index of the fluid of the particle, in the order of the context's fluids.
+    fluid: usize,
+    position: [Real; 3],
+    velocity: [Real; 3],
+    density: Real,
+}
+
+impl SalvaContext {
+    /// Writes the positions, velocities and densities of every fluid particle of this context.
+    ///
+    /// PLY and VTK points are always 3D, with a zero `z` coordinate in 2D. Particles are tagged
+    /// with the index of their fluid, in the order of the context's fluids.
+    ///
+    /// Salva's solver doesn't expose its densities, so they are evaluated again for the export,
+    /// with [`Self::compute_densities`].
+    pub fn export_particles(&self, format: ParticleExportFormat, out: &mut impl Write) -> io::Result<()> {
+        let densities = self.compute_densities();
+        let particles: Vec<ExportedParticle> = self
+            .liquid_world
+            .fluids()
+            .iter()
+            .enumerate()
+            .flat_map(|(fluid_index, (handle, fluid))| {
+                let densities = &densities[&handle];
+                fluid
+                    .positions
+                    .iter()
+                    .zip(fluid.velocities.iter())
+                    .zip(densities.iter())
+                    .map(move |((pos, vel), density)| ExportedParticle {
+                        fluid: fluid_index,
+                        position: to_xyz(Vect::from(*pos)),
+                        velocity: to_xyz(Vect::from(*vel)),
+                        density: *density,
+                    })
+            })
+            .collect();
+
+        match format {
+            ParticleExportFormat::Ply => write_ply(&particles, out),
+            ParticleExportFormat::Vtk => write_vtk(&particles, out),
+            ParticleExportFormat::Csv => write_csv(&particles, out),
+        }
+    }
+}
+
+/// Add this to a salva context entity to export its particles to a file every
+/// [`Self::every_n_steps`] salva solver steps, as counted by [`SalvaStepStats::solver_steps`].
+/// Exports happen after the simulation steps of a frame, so at most once per frame.
+///
+/// Files are named `frame_00000.<extension>` in [`Self::directory`], numbered by export. The
+/// directory is created if needed.
+#[derive(Component, Reflect, Clone, Debug)]
+#[reflect(Component)]
+pub struct FluidExporter {
+    pub format: ParticleExportFormat,
+    pub directory: PathBuf,
+    pub every_n_steps: u32,
+    steps: u32,
+    exported_frames: u32,
+    created_directory: Option<PathBuf>,
+}
+
+impl FluidExporter {
+    pub fn new(format: ParticleExportFormat, directory: impl Into<PathBuf>) -> Self {
+        Self {
+            format,
+            directory: directory.into(),
+            every_n_steps: 1,
+            steps: 0,
+            exported_frames: 0,
+            created_directory: None,
+        }
+    }
+
+    pub fn every_n_steps(mut self, n: u32) -> Self {
+        self.every_n_steps = n;
+        self
+    }
+
+    /// The number of files exported so far.
+    pub fn exported_frames(&self) -> u32 {
+        self.exported_frames
+    }
+}
+
+/// Exports the particles of the contexts with a [`FluidExporter`] after their simulation steps.
+pub fn export_fluid_frames(
+    mut contexts: Query<(&SalvaContext, &SalvaStepStats, &mut FluidExporter)>,
+) {
+    for (context, stats, mut exporter) in contexts.iter_mut() {
+        let every_n_steps = exporter.every_n_steps.max(1);
+        exporter.steps += stats.solver_steps;
+        if stats.solver_steps == 0 || exporter.steps < every_n_steps {
+            continue;
+        }
+        exporter.steps %= every_n_steps;
+
+        if exporter.created_directory.as_ref() != Some(&exporter.directory) {
+            if let Err(err) = fs::create_dir_all(&exporter.directory) {
+                error!(
+                    "Couldn't create the fluid export directory {}: {err}",
+                    exporter.directory.display()
+                );
+                continue;
+            }
+            exporter.created_directory = Some(exporter.directory.clone());
+        }
+
+        let path = exporter.directory.join(format!(
+            "frame_{:05}.{}",
+            exporter.exported_frames,
+            exporter.format.extension()
+        ));
+        let result = File::create(&path).and_then(|file| {
+            let mut out = BufWriter::new(file);
+            context.export_particles(exporter.format, &mut out)?;
+            out.flush()
+        });
+        match result {
+            Ok(()) => exporter.exported_frames += 1,
+            Err(err) => error!("Couldn't export fluid particles to {}: {err}", path.display()),
+        }
+    }
+}
+
+fn write_ply(particles: &[ExportedParticle], out: &mut impl Write) -> io::Result<()> {
+    writeln!(out, "ply")?;
+    writeln!(out, "format ascii 1.0")?;
+    writeln!(out, "element vertex {}", particles.len())?;
+    for property in ["x", "y", "z", "vx", "vy", "vz", "density"] {
+        writeln!(out, "property float {property}")?;
+    }
+    writeln!(out, "property int fluid")?;
+    writeln!(out, "end_header")?;
+    for p in particles {
+        let [x, y, z] = p.position;
+        let [vx, vy, vz] = p.velocity;
+        writeln!(out, "{x} {y} {z} {vx} {vy} {vz} {} {}", p.density, p.fluid)?;
+    }
+    Ok(())
+}
+
+fn write_vtk(particles: &[ExportedParticle], out: &mut impl Write) -> io::Result<()> {
+    writeln!(out, "# vtk DataFile Version 3.0")?;
+    writeln!(out, "salva fluid particles")?;
+    writeln!(out, "ASCII")?;
+    writeln!(out, "DATASET POLYDATA")?;
+    writeln!(out, "POINTS {} float", particles.len())?;
+    for p in particles {
+        let [x, y, z] = p.position;
+        writeln!(out, "{x} {y} {z}")?;
+    }
+    writeln!(out, "VERTICES {} {}", particles.len(), particles.len() * 2)?;
+    for i in 0..particles.len() {
+        writeln!(out, "1 {i}")?;
+    }
+    writeln!(out, "POINT_DATA {}", particles.len())?;
+    writeln!(out, "VECTORS velocity float")?;
+    for p in particles {
+        let [vx, vy, vz] = p.velocity;
+        writeln!(out, "{vx} {vy} {vz}")?;
+    }
+    writeln!(out, "SCALARS density float 1")?;
+    writeln!(out, "LOOKUP_TABLE default")?;
+    for p in particles {
+        writeln!(out, "{}", p.density)?;
+    }
+    writeln!(out, "SCALARS fluid int 1")?;
+    writeln!(out, "LOOKUP_TABLE default")?;
+    for p in particles {
+        writeln!(out, "{}", p.fluid)?;
+    }
+    Ok(())
+}
+
+fn write_csv(particles: &[ExportedParticle], out: &mut impl Write) -> io::Result<()> {
+    #[cfg(feature = "dim2")]
+    writeln!(out, "fluid,x,y,vx,vy,density")?;
+    #[cfg(feature = "dim3")]
+    writeln!(out, "fluid,x,y,z,vx,vy,vz,density")?;
+    for p in particles {
+        let [x, y, _z] = p.position;
+        let [vx, vy, _vz] = p.velocity;
+        #[cfg(feature = "dim2")]
+        writeln!(out, "{},{x},{y},{vx},{vy},{}", p.fluid, p.density)?;
+        #[cfg(feature = "dim3")]
+        writeln!(out, "{},{x},{y},{_z},{vx},{vy},{_vz},{}", p.fluid, p.density)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "dim2")]
+fn to_xyz(v: Vect) -> [Real; 3] {
+    [v.x, v.y, 0.0]
+}
+
+#[cfg(feature = "dim3")]
+fn to_xyz(v: Vect) -> [Real; 3] {
+    v.to_array()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn particles() -> Vec<ExportedParticle> {
+        vec![
+            ExportedParticle {
+                fluid: 0,
+                position: [1.0, 2.0, 0.0],
+                velocity: [0.5, -0.5, 0.0],
+                density: 1000.0,
+            },
+            ExportedParticle {
+                fluid: 1,
+                position: [-1.0, 0.25, 0.0],
+                velocity: [0.0, 0.0, 0.0],
+                density: 998.5,
+            },
+        ]
+    }
+
+    fn written(write: fn(&[ExportedParticle], &mut Vec<u8>) -> io::Result<()>) -> Vec<String> {
+        let mut out = Vec::new();
+        write(&particles(), &mut out).unwrap();
+        String::from_utf8(out).unwrap().lines().map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn ply_has_a_vertex_per_particle() {
+        let lines = written(write_ply);
+        assert_eq!(lines[..3], ["ply", "format ascii 1.0", "element vertex 2"]);
+        let end_header = lines.iter().position(|line| line == "end_header").unwrap();
+        assert_eq!(lines[end_header + 1..], ["1 2 0 0.5 -0.5 0 1000 0", "-1 0.25 0 0 0 0 998.5 1"]);
+    }
+
+    #[test]
+    fn vtk_has_points_vertices_and_point_data() {
+        let lines = written(write_vtk);
+        let section = |header: &str, len: usize| {
+            let start = lines.iter().position(|line| line == header).unwrap() + 1;
+            lines[start..start + len].to_vec()
+        };
+        assert_eq!(section("POINTS 2 float", 2), ["1 2 0", "-1 0.25 0"]);
+        assert_eq!(section("VERTICES 2 4", 2), ["1 0", "1 1"]);
+        assert_eq!(section("VECTORS velocity float", 2), ["0.5 -0.5 0", "0 0 0"]);
+        assert_eq!(section("SCALARS density float 1", 3)[1..], ["1000", "998.5"]);
+        assert_eq!(section("SCALARS fluid int 1", 3)[1..], ["0", "1"]);
+    }
+
+    #[test]
+    fn csv_has_a_row_per_particle() {
+        let lines = written(write_csv);
+        #[cfg(feature = "dim2")]
+        assert_eq!(lines, ["fluid,x,y,vx,vy,density", "0,1,2,0.5,-0.5,1000", "1,-1,0.25,0,0,998.5"]);
+        #[cfg(feature = "dim3")]
+        assert_eq!(
+            lines,
+            [
+                "fluid,x,y,z,vx,vy,vz,density",
+                "0,1,2,0,0.5,-0.5,0,1000",
+                "1,-1,0.25,0,0,0,0,998.5",
+            ]
+        );
+    }
+}
@@ -1,8 +1,12 @@
 use std::ops::{Deref, DerefMut};
 use crate::math::{Real, Vect};
-use bevy::prelude::{Component, Reflect};
+use bevy::prelude::{Component, Reflect, ReflectComponent, ReflectDefault};
 use salva::object::interaction_groups::InteractionGroups;
 use salva::{object::FluidHandle, solver::NonPressureForce};
+use salva::solver::{
+    Akinci2013SurfaceTension, ArtificialViscosity, Becker2009Elasticity, DFSPHViscosity,
+    He2014SurfaceTension, WCSPHSurfaceTension, XSPHViscosity,
+};
 #[cfg(feature = "serialize")]
 use serde::{Deserialize, Serialize};
 
@@ -10,7 +14,8 @@ use serde::{Deserialize, Serialize};
 pub struct SalvaFluidHandle(pub FluidHandle);
 
 /// Adding this to an entity makes it a fluid entity.
-#[derive(Component, Default, Clone)]
+#[derive(Component, Reflect, Default, Clone)]
+#[reflect(Component, Default)]
 #[require(FluidVelocities, FluidAccelerations)]
 #[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct FluidPositions(pub Vec<Vect>);
@@ -24,7 +29,8 @@ impl DerefMut for FluidPositions {
     fn deref_mut(&mut self) -> &mut Self::Target { &mut self.0 }
 }
 
-#[derive(Component, Default, Clone)]
+#[derive(Component, Reflect, Default, Clone)]
+#[reflect(Component, Default)]
 #[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct FluidVelocities(pub Vec<Vect>);
 
@@ -37,7 +43,8 @@ impl DerefMut for FluidVelocities {
     fn deref_mut(&mut self) -> &mut Self::Target { &mut self.0 }
 }
 
-#[derive(Component, Default, Clone)]
+#[derive(Component, Reflect, Default, Clone)]
+#[reflect(Component, Default)]
 #[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct FluidAccelerations(pub Vec<Vect>);
 
@@ -51,7 +58,8 @@ impl DerefMut for FluidAccelerations {
 }
 
 /// The rest density of a fluid (default 1000.0)
-#[derive(Component, Copy, Clone)]
+#[derive(Component, Reflect, Copy, Clone)]
+#[reflect(Component, Default)]
 #[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct FluidDensity {
     pub density0: Real,
@@ -63,15 +71,219 @@ impl Default for FluidDensity {
     }
 }
 
+/// Add this to a fluid entity to read back the density of each of its particles after every
+/// simulation step.
+///
+/// Densities are the SPH sum over the neighbor fluid particles and boundary samples, as in
+/// salva's solver, evaluated at the particle positions at the end of the step.
+#[derive(Component, Reflect, Default, Clone)]
+#[reflect(Component, Default)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct FluidDensities(pub Vec<Real>);
+
+impl Deref for FluidDensities {
+    type Target = Vec<Real>;
+    fn deref(&self) -> &Self::Target { &self.0 }
+}
+
+impl DerefMut for FluidDensities {
+    fn deref_mut(&mut self) -> &mut Self::Target { &mut self.0 }
+}
+
+/// Add this to a fluid entity to read back an estimate of the pressure of each of its particles
+/// after every simulation step.
+///
+/// Salva's DFSPH solver doesn't expose the pressures of its solve, so these values are estimates:
+/// they are derived from the particle densities of [`FluidDensities`] with the linear equation of
+/// state `p = stiffness * max(ρ / ρ0 - 1, 0)`, where `ρ0` is the rest density of the fluid. See
+/// [`Self::estimate`].
+#[derive(Component, Reflect, Clone)]
+#[reflect(Component, Default)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct FluidPressures {
+    pub pressures: Vec<Real>,
+    /// Pressure of a particle compressed to twice the rest density (default 1.0).
+    pub stiffness: Real,
+}
+
+impl FluidPressures {
+    /// The estimated pressure of a particle of the given density, in a fluid of rest density
+    /// `density0`. Particles less dense than the rest density have no pressure: like salva's
+    /// solver, the estimate doesn't pull particles together.
+    pub fn estimate(density: Real, density0: Real, stiffness: Real) -> Real {
+        stiffness * (density / density0 - 1.0).max(0.0)
+    }
+}
+
+impl Default for FluidPressures {
+    fn default() -> Self {
+        Self { pressures: Vec::new(), stiffness: 1.0 }
+    }
+}
+
+impl Deref for FluidPressures {
+    type Target = Vec<Real>;
+    fn deref(&self) -> &Self::Target { &self.pressures }
+}
+
+impl DerefMut for FluidPressures {
+    fn deref_mut(&mut self) -> &mut Self::Target { &mut self.pressures }
+}
+
+/// A serializable description of one of salva's non-pressure forces.
+///
+/// Unlike `Box<dyn NonPressureForce>`, this can be stored in snapshots and built again into
+/// a working force with [`Self::build`].
+#[derive(Reflect, Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub enum FluidNonPressureForce {
+    /// See [`XSPHViscosity`].
+    XsphViscosity {
+        fluid_viscosity_coefficient: Real,
+        boundary_viscosity_coefficient: Real,
+    },
+    /// See [`ArtificialViscosity`].
+    ArtificialViscosity {
+        fluid_viscosity_coefficient: Real,
+        boundary_viscosity_coefficient: Real,
+    },
+    /// See [`DFSPHViscosity`].
+    DfsphViscosity { viscosity_coefficient: Real },
+    /// See [`Becker2009Elasticity`].
+    Becker2009Elasticity {
+        young_modulus: Real,
+        poisson_ratio: Real,
+        nonlinear_strain: bool,
+    },
+    /// See [`WCSPHSurfaceTension`].
+    WcsphSurfaceTension {
+        fluid_tension_coefficient: Real,
+        boundary_adhesion_coefficient: Real,
+    },
+    /// See [`Akinci2013SurfaceTension`].
+    Akinci2013SurfaceTension {
+        fluid_tension_coefficient: Real,
+        boundary_adhesion_coefficient: Real,
+    },
+    /// See [`He2014SurfaceTension`].
+    He2014SurfaceTension {
+        fluid_tension_coefficient: Real,
+        boundary_adhesion_coefficient: Real,
+    },
+}
+
+impl FluidNonPressureForce {
+    /// Builds the salva force described by `self`.
+    pub fn build(&self) -> Box<dyn NonPressureForce> {
+        match *self {
+            Self::XsphViscosity { fluid_viscosity_coefficient, boundary_viscosity_coefficient } => {
+                Box::new(XSPHViscosity::new(fluid_viscosity_coefficient, boundary_viscosity_coefficient))
+            }
+            Self::ArtificialViscosity { fluid_viscosity_coefficient, boundary_viscosity_coefficient } => {
+                Box::new(ArtificialViscosity::new(fluid_viscosity_coefficient, boundary_viscosity_coefficient))
+            }
+            Self::DfsphViscosity { viscosity_coefficient } => {
+                Box::new(DFSPHViscosity::new(viscosity_coefficient))
+            }
+            Self::Becker2009Elasticity { young_modulus, poisson_ratio, nonlinear_strain } => {
+                Box::new(Becker2009Elasticity::new(young_modulus, poisson_ratio, nonlinear_strain))
+            }
+            Self::WcsphSurfaceTension { fluid_tension_coefficient, boundary_adhesion_coefficient } => {
+                Box::new(WCSPHSurfaceTension::new(fluid_tension_coefficient, boundary_adhesion_coefficient))
+            }
+            Self::Akinci2013SurfaceTension { fluid_tension_coefficient, boundary_adhesion_coefficient } => {
+                Box::new(Akinci2013SurfaceTension::new(fluid_tension_coefficient, boundary_adhesion_coefficient))
+            }
+            Self::He2014SurfaceTension { fluid_tension_coefficient, boundary_adhesion_coefficient } => {
+                Box::new(He2014SurfaceTension::new(fluid_tension_coefficient, boundary_adhesion_coefficient))
+            }
+        }
+    }
+}
+
+/// Non-pressure forces given to the fluid when it is created.
+///
+/// These are trait objects, so they can't be reflected or saved in scenes; prefer
+/// [`FluidNonPressureForceConfigs`] for the forces it describes.
 #[derive(Component)]
 pub struct FluidNonPressureForces(pub Vec<Box<dyn NonPressureForce>>);
 
+/// Descriptions of non-pressure forces given to the fluid when it is created.
+///
+/// Unlike [`FluidNonPressureForces`], this stays on the fluid entity and can be reflected, so
+/// fluids keep their forces when saved in and loaded from a scene.
+#[derive(Component, Reflect, Default, Clone, Debug)]
+#[reflect(Component, Default)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct FluidNonPressureForceConfigs(pub Vec<FluidNonPressureForce>);
+
 #[derive(Component)]
 pub struct AppendNonPressureForces(pub Vec<Box<dyn NonPressureForce>>);
 
 #[derive(Component)]
 pub struct RemoveNonPressureForcesAt(pub Vec<usize>);
 
+/// An edit of the particles of a fluid. See [`FluidParticleEdits`].
+#[derive(Reflect, Clone, Debug, PartialEq)]
+pub enum FluidParticleEdit {
+    /// Appends particles at the given positions, with the given velocities or at rest.
+    Add {
+        positions: Vec<Vect>,
+        velocities: Option<Vec<Vect>>,
+    },
+    /// Removes the particles at the given indices.
+    Remove(Vec<usize>),
+    /// Reorders the particles: the particle `i` after the edit is the particle `order[i]`
+    /// before it.
+    Permute(Vec<usize>),
+}
+
+impl FluidParticleEdit {
+    /// Applies this edit to a per-particle buffer. `added(k)` gives the value of the `k`-th
+    /// particle added by [`FluidParticleEdit::Add`].
+    pub fn apply_to<T: Clone>(&self, values: &mut Vec<T>, mut added: impl FnMut(usize) -> T) {
+        match self {
+            FluidParticleEdit::Add { positions, .. } => {
+                values.extend((0..positions.len()).map(&mut added));
+            }
+            FluidParticleEdit::Remove(indices) => {
+                let mut keep = vec![true; values.len()];
+                for i in indices {
+                    if let Some(keep) = keep.get_mut(*i) {
+                        *keep = false;
+                    }
+                }
+                let mut keep = keep.into_iter();
+                values.retain(|_| keep.next().unwrap_or(true));
+            }
+            FluidParticleEdit::Permute(order) => {
+                *values = order.iter().filter_map(|i| values.get(*i).cloned()).collect();
+            }
+        }
+    }
+}
+
+/// Add this to a fluid entity to add, remove or reorder some of its particles without resetting
+/// the velocities of the others (as replacing [`FluidPositions`] does).
+///
+/// Edits are applied in order during [`SalvaSimulationSet::SyncBackend`](crate::plugin::SalvaSimulationSet),
+/// then moved to the [`FluidParticleEditLog`] so that per-particle attribute channels can apply
+/// them too.
+#[derive(Component, Reflect, Clone, Debug, Default)]
+#[reflect(Component, Default)]
+#[require(FluidParticleEditLog)]
+pub struct FluidParticleEdits(pub Vec<FluidParticleEdit>);
+
+/// The particle edits most recently applied to a fluid.
+#[derive(Component, Reflect, Clone, Debug, Default)]
+#[reflect(Component, Default)]
+pub struct FluidParticleEditLog {
+    /// Incremented every time edits are applied.
+    pub generation: u64,
+    /// The edits applied at [`Self::generation`].
+    pub edits: Vec<FluidParticleEdit>,
+}
+
 /// A bit mask identifying groups for fluid interactions.
 #[derive(Component, Reflect, Copy, Clone, Debug, PartialEq, Eq, Hash)]
 #[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
@@ -173,6 +385,7 @@ impl Default for Group {
 /// (self.memberships & rhs.filter) != 0 && (rhs.memberships & self.filter) != 0
 /// ```
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Component, Reflect)]
+#[reflect(Component, Default)]
 #[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct FluidInteractionGroups {
     /// Groups memberships.
@@ -210,3 +423,44 @@ impl From<FluidInteractionGroups> for InteractionGroups {
         }
     }
 }
+
+impl From<InteractionGroups> for FluidInteractionGroups {
+    fn from(groups: InteractionGroups) -> Self {
+        FluidInteractionGroups {
+            memberships: Group::from_bits_retain(groups.memberships.bits()),
+            filters: Group::from_bits_retain(groups.filter.bits()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edited(edit: FluidParticleEdit) -> Vec<u32> {
+        let mut values = vec![10, 11, 12, 13];
+        edit.apply_to(&mut values, |k| 20 + k as u32);
+        values
+    }
+
+    #[test]
+    fn add_appends_the_added_values() {
+        let edit = FluidParticleEdit::Add {
+            positions: vec![Vect::ZERO; 2],
+            velocities: None,
+        };
+        assert_eq!(edited(edit), [10, 11, 12, 13, 20, 21]);
+    }
+
+    #[test]
+    fn remove_ignores_duplicate_and_out_of_range_indices() {
+        assert_eq!(edited(FluidParticleEdit::Remove(vec![2, 0])), [11, 13]);
+        assert_eq!(edited(FluidParticleEdit::Remove(vec![1, 1, 7])), [10, 12, 13]);
+    }
+
+    #[test]
+    fn permute_reorders_and_drops_out_of_range_indices() {
+        assert_eq!(edited(FluidParticleEdit::Permute(vec![3, 2, 1, 0])), [13, 12, 11, 10]);
+        assert_eq!(edited(FluidParticleEdit::Permute(vec![1, 9, 0])), [11, 10]);
+    }
+}
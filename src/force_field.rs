@@ -0,0 +1,371 @@
+use crate::math::{Real, Vect};
+use crate::plugin::systems::is_simulation_active;
+use crate::plugin::{
+    DefaultSalvaContext, SalvaConfiguration, SalvaContext, SalvaContextEntityLink,
+    SimulationToRenderTime, TimestepMode,
+};
+#[cfg(feature = "rapier")]
+use crate::rapier_integration::SalvaRapierCoupling;
+use bevy::prelude::{
+    Component, Entity, Event, EventReader, GlobalTransform, Local, Query, Reflect, Res, Time, With,
+};
+#[cfg(feature = "rapier")]
+use bevy_rapier::prelude::RapierConfiguration;
+use salva::math::Vector;
+
+/// Add this to an entity with a [`GlobalTransform`] to accelerate the fluid particles around it.
+///
+/// The field is centered on the entity's translation and affects the particles of the entity's
+/// [`SalvaContext`] (see [`SalvaContextEntityLink`]) within [`Self::radius`]. It is applied to
+/// particle velocities right before each simulation step.
+#[derive(Component, Clone, Debug, PartialEq, Reflect)]
+pub struct FluidForceField {
+    /// What the field does to the particles in range.
+    pub kind: FluidForceFieldKind,
+    /// Particles farther than this from the center of the field are unaffected.
+    pub radius: Real,
+    /// How the field weakens with the distance to its center.
+    pub falloff: FluidForceFalloff,
+}
+
+impl FluidForceField {
+    pub fn new(kind: FluidForceFieldKind, radius: Real) -> Self {
+        Self {
+            kind,
+            radius,
+            falloff: FluidForceFalloff::None,
+        }
+    }
+
+    pub fn with_falloff(mut self, falloff: FluidForceFalloff) -> Self {
+        self.falloff = falloff;
+        self
+    }
+
+    /// The acceleration applied by this field to a particle at `offset` from its center, given the
+    /// gravity of the [`SalvaContext`] the particle belongs to.
+    pub fn acceleration(&self, offset: Vect, context_gravity: Vect) -> Vect {
+        let weight = self.falloff.weight_at(offset.length(), self.radius);
+        if weight <= 0.0 {
+            return Vect::ZERO;
+        }
+
+        let acceleration = match &self.kind {
+            FluidForceFieldKind::Attractor { strength } => {
+                -offset.normalize_or_zero() * *strength
+            }
+            #[cfg(feature = "dim2")]
+            FluidForceFieldKind::Vortex { strength } => {
+                offset.normalize_or_zero().perp() * *strength
+            }
+            #[cfg(feature = "dim3")]
+            FluidForceFieldKind::Vortex { axis, strength } => {
+                axis.cross(offset).normalize_or_zero() * *strength
+            }
+            FluidForceFieldKind::Directional { acceleration } => *acceleration,
+            FluidForceFieldKind::Gravity { gravity } => *gravity - context_gravity,
+        };
+
+        acceleration * weight
+    }
+}
+
+/// The effect of a [`FluidForceField`].
+#[derive(Clone, Debug, PartialEq, Reflect)]
+pub enum FluidForceFieldKind {
+    /// Pulls particles towards the center of the field, e.g. the radial gravity of a planetoid.
+    /// A negative strength pushes them away.
+    Attractor { strength: Real },
+    /// Swirls particles counterclockwise around the center of the field, e.g. a whirlpool.
+    /// A negative strength swirls them clockwise.
+    #[cfg(feature = "dim2")]
+    Vortex { strength: Real },
+    /// Swirls particles around the given axis going through the center of the field, e.g. a
+    /// whirlpool. The rotation follows the right-hand rule.
+    #[cfg(feature = "dim3")]
+    Vortex { axis: Vect, strength: Real },
+    /// Applies the same acceleration to every particle, e.g. a fan or wind zone.
+    Directional { acceleration: Vect },
+    /// Replaces the gravity of the [`SalvaContext`] by the given gravity.
+    Gravity { gravity: Vect },
+}
+
+/// How a [`FluidForceField`] weakens with the distance to its center.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, Reflect)]
+pub enum FluidForceFalloff {
+    /// The field has full strength everywhere within its radius.
+    #[default]
+    None,
+    /// The strength decreases linearly to zero at the radius.
+    Linear,
+    /// The strength decreases quadratically to zero at the radius.
+    Quadratic,
+}
+
+impl FluidForceFalloff {
+    /// The strength multiplier at the given distance, relative to the radius of the field.
+    pub fn weight(&self, relative_distance: Real) -> Real {
+        let t = (1.0 - relative_distance).clamp(0.0, 1.0);
+        match self {
+            FluidForceFalloff::None => 1.0,
+            FluidForceFalloff::Linear => t,
+            FluidForceFalloff::Quadratic => t * t,
+        }
+    }
+
+    /// The strength multiplier at `distance` from the center of a region of the given `radius`,
+    /// or zero outside of it. A region with a non-positive radius affects nothing.
+    pub fn weight_at(&self, distance: Real, radius: Real) -> Real {
+        if radius <= 0.0 || distance > radius {
+            return 0.0;
+        }
+        self.weight(distance / radius)
+    }
+}
+
+/// The system that applies every [`FluidForceField`] to the particles in range.
+///
+/// Fields are skipped during the ticks where their [`SalvaContext`] isn't stepped.
+pub fn apply_fluid_force_fields(
+    fields: Query<(
+        &FluidForceField,
+        &GlobalTransform,
+        Option<&SalvaContextEntityLink>,
+    )>,
+    mut salva_contexts: Query<(&mut SalvaContext, &SalvaConfiguration, &SimulationToRenderTime)>,
+    q_default_context: Query<Entity, With<DefaultSalvaContext>>,
+    #[cfg(feature = "rapier")]
+    rapier_couplings: Query<&SalvaRapierCoupling>,
+    #[cfg(feature = "rapier")]
+    rapier_configs: Query<&RapierConfiguration>,
+    timestep_mode: Res<TimestepMode>,
+    time: Res<Time>,
+) {
+    if fields.is_empty() {
+        return;
+    }
+
+    for (field, transform, link) in fields.iter() {
+        let Some(context_entity) = link
+            .map(|link| link.0)
+            .or_else(|| q_default_context.single().ok())
+        else {
+            continue;
+        };
+        let Ok((mut context, config, sim_to_render_time)) = salva_contexts.get_mut(context_entity)
+        else {
+            continue;
+        };
+        #[cfg(not(feature = "rapier"))]
+        let is_active = is_simulation_active(config);
+        #[cfg(feature = "rapier")]
+        let is_active = is_simulation_active(
+            config,
+            rapier_couplings.get(context_entity).ok(),
+            &rapier_configs,
+        );
+        let dt = timestep_mode.step_delta(&time, sim_to_render_time);
+        if !is_active || dt <= 0.0 {
+            continue;
+        }
+
+        #[cfg(feature = "dim2")]
+        let center = transform.translation().truncate();
+        #[cfg(feature = "dim3")]
+        let center = transform.translation();
+
+        let gravity = config.gravity;
+        let context = &mut *context;
+        let fluids = context.liquid_world.fluids_mut();
+        for (pos, (handle, i)) in context
+            .particle_index
+            .fluid_particles_in_radius(center, field.radius)
+        {
+            let acceleration = field.acceleration(*pos - center, gravity);
+            if acceleration == Vect::ZERO {
+                continue;
+            }
+            if let Some(vel) = fluids
+                .get_mut(*handle)
+                .and_then(|fluid| fluid.velocities.get_mut(*i))
+            {
+                *vel += Vector::from(acceleration * dt);
+            }
+        }
+    }
+}
+
+/// Event that pushes the fluid particles of a [`SalvaContext`] within a region.
+///
+/// Impulses are applied to particle velocities right before the next simulation step, e.g. for
+/// explosions, swimming characters or mouse-drag interaction. For persistent effects, see
+/// [`FluidForceField`].
+#[derive(Event, Copy, Clone, Debug, PartialEq)]
+pub struct FluidImpulse {
+    /// The [`SalvaContext`] entity whose particles are pushed.
+    /// If `None`, the [`DefaultSalvaContext`] is used.
+    pub context: Option<Entity>,
+    /// The center of the affected region.
+    pub center: Vect,
+    /// Particles farther than this from the center are unaffected.
+    pub radius: Real,
+    /// How the particles are pushed.
+    pub impulse: FluidImpulseKind,
+    /// How the impulse weakens with the distance to the center.
+    pub falloff: FluidForceFalloff,
+}
+
+impl FluidImpulse {
+    pub fn new(center: Vect, radius: Real, impulse: FluidImpulseKind) -> Self {
+        Self {
+            context: None,
+            center,
+            radius,
+            impulse,
+            falloff: FluidForceFalloff::None,
+        }
+    }
+
+    pub fn with_falloff(mut self, falloff: FluidForceFalloff) -> Self {
+        self.falloff = falloff;
+        self
+    }
+
+    pub fn in_context(mut self, context: Entity) -> Self {
+        self.context = Some(context);
+        self
+    }
+
+    /// The velocity change of a particle at `offset` from the center, when the next simulation
+    /// step advances by `dt`.
+    pub fn velocity_change(&self, offset: Vect, dt: Real) -> Vect {
+        let weight = self.falloff.weight_at(offset.length(), self.radius);
+        if weight <= 0.0 {
+            return Vect::ZERO;
+        }
+
+        let velocity_change = match self.impulse {
+            FluidImpulseKind::Linear(impulse) => impulse,
+            FluidImpulseKind::Radial(strength) => offset.normalize_or_zero() * strength,
+            FluidImpulseKind::Acceleration(acceleration) => acceleration * dt,
+        };
+
+        velocity_change * weight
+    }
+}
+
+/// How a [`FluidImpulse`] pushes particles.
+#[derive(Copy, Clone, Debug, PartialEq, Reflect)]
+pub enum FluidImpulseKind {
+    /// Changes particle velocities by the given amount, i.e. an impulse per unit of mass.
+    Linear(Vect),
+    /// Pushes particles away from the center with the given velocity change, e.g. an explosion.
+    /// A negative strength pulls them in.
+    Radial(Real),
+    /// Applies the given acceleration to the particles during the next simulation step.
+    Acceleration(Vect),
+}
+
+impl SalvaContext {
+    /// Applies a [`FluidImpulse`] to the particles of this context, given the amount of time `dt`
+    /// the next simulation step advances by. The [`FluidImpulse::context`] is ignored.
+    ///
+    /// Particles are found with the [`Self::particle_index`], so particles moved by hand since
+    /// the last step are found at their former position.
+    pub fn apply_impulse(&mut self, impulse: &FluidImpulse, dt: Real) {
+        let fluids = self.liquid_world.fluids_mut();
+        for (pos, (handle, i)) in self
+            .particle_index
+            .fluid_particles_in_radius(impulse.center, impulse.radius)
+        {
+            let velocity_change = impulse.velocity_change(*pos - impulse.center, dt);
+            if velocity_change == Vect::ZERO {
+                continue;
+            }
+            if let Some(vel) = fluids
+                .get_mut(*handle)
+                .and_then(|fluid| fluid.velocities.get_mut(*i))
+            {
+                *vel += Vector::from(velocity_change);
+            }
+        }
+    }
+}
+
+/// The system that applies the [`FluidImpulse`] events sent since its last run.
+///
+/// Impulses sent to a [`SalvaContext`] that isn't stepped during this tick, which happens with
+/// [`TimestepMode::Interpolated`], are kept until it is.
+pub fn apply_fluid_impulses(
+    mut impulses: EventReader<FluidImpulse>,
+    mut pending: Local<Vec<FluidImpulse>>,
+    mut salva_contexts: Query<(&mut SalvaContext, &SimulationToRenderTime)>,
+    q_default_context: Query<Entity, With<DefaultSalvaContext>>,
+    timestep_mode: Res<TimestepMode>,
+    time: Res<Time>,
+) {
+    let impulses: Vec<FluidImpulse> = pending.drain(..).chain(impulses.read().copied()).collect();
+
+    for impulse in impulses {
+        let Some(context_entity) = impulse
+            .context
+            .or_else(|| q_default_context.single().ok())
+        else {
+            continue;
+        };
+        let Ok((mut context, sim_to_render_time)) = salva_contexts.get_mut(context_entity) else {
+            continue;
+        };
+        let dt = timestep_mode.step_delta(&time, sim_to_render_time);
+        if dt <= 0.0 {
+            pending.push(impulse);
+            continue;
+        }
+        context.apply_impulse(&impulse, dt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn falloff_weights() {
+        for falloff in [
+            FluidForceFalloff::None,
+            FluidForceFalloff::Linear,
+            FluidForceFalloff::Quadratic,
+        ] {
+            assert_eq!(falloff.weight_at(0.0, 2.0), 1.0);
+            assert_eq!(falloff.weight_at(2.5, 2.0), 0.0);
+        }
+        assert_eq!(FluidForceFalloff::None.weight_at(1.0, 2.0), 1.0);
+        assert_eq!(FluidForceFalloff::Linear.weight_at(1.0, 2.0), 0.5);
+        assert_eq!(FluidForceFalloff::Quadratic.weight_at(1.0, 2.0), 0.25);
+        assert_eq!(FluidForceFalloff::Linear.weight_at(2.0, 2.0), 0.0);
+    }
+
+    #[test]
+    fn non_positive_radii_affect_nothing() {
+        for radius in [0.0, -1.0] {
+            assert_eq!(FluidForceFalloff::None.weight_at(0.0, radius), 0.0);
+
+            let field = FluidForceField::new(
+                FluidForceFieldKind::Directional { acceleration: Vect::X },
+                radius,
+            );
+            assert_eq!(field.acceleration(Vect::ZERO, Vect::ZERO), Vect::ZERO);
+
+            let impulse = FluidImpulse::new(Vect::ZERO, radius, FluidImpulseKind::Linear(Vect::X));
+            assert_eq!(impulse.velocity_change(Vect::ZERO, 1.0), Vect::ZERO);
+        }
+    }
+
+    #[test]
+    fn attractors_pull_towards_the_center() {
+        let field = FluidForceField::new(FluidForceFieldKind::Attractor { strength: 2.0 }, 10.0)
+            .with_falloff(FluidForceFalloff::Linear);
+        let acceleration = field.acceleration(Vect::X * 5.0, Vect::ZERO);
+        assert!((acceleration - Vect::X * -1.0).length() < 1.0e-6);
+    }
+}
@@ -35,7 +35,17 @@ pub mod math {
 }
 
 pub mod plugin;
+pub mod render;
+pub mod surface;
 pub mod fluid;
+pub mod color_mixing;
+pub mod diagnostics;
+pub mod export;
+pub mod force_field;
+pub mod material;
+pub mod pipeline;
+pub mod particle_attributes;
+pub mod recording;
 #[cfg(feature = "rapier")]
 #[allow(clippy::type_complexity)]
 pub mod rapier_integration;
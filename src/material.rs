@@ -0,0 +1,133 @@
+use crate::fluid::{FluidInteractionGroups, FluidNonPressureForce};
+use crate::math::Real;
+use bevy::prelude::*;
+#[cfg(feature = "serialize")]
+use bevy::asset::{io::Reader, AssetLoader, LoadContext};
+#[cfg(feature = "serialize")]
+use serde::{Deserialize, Serialize};
+
+/// A reusable fluid preset, e.g. "water", "honey" or "lava".
+///
+/// Give it to a fluid entity with [`FluidMaterialHandle`]. With the `serialize` feature,
+/// materials can be loaded from `.fluid.ron` files:
+///
+/// ```ron
+/// (
+///     density0: 1400.0,
+///     nonpressure_forces: [
+///         XsphViscosity(fluid_viscosity_coefficient: 2.0, boundary_viscosity_coefficient: 0.0),
+///         WcsphSurfaceTension(fluid_tension_coefficient: 1.0, boundary_adhesion_coefficient: 0.0),
+///     ],
+///     debug_color: Some(Srgba((red: 0.9, green: 0.6, blue: 0.1, alpha: 1.0))),
+/// )
+/// ```
+#[derive(Asset, Reflect, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serialize", serde(default))]
+pub struct FluidMaterial {
+    /// The rest density of the fluid.
+    pub density0: Real,
+    /// The viscosity, surface tension and elasticity models of the fluid, with their
+    /// coefficients.
+    pub nonpressure_forces: Vec<FluidNonPressureForce>,
+    pub interaction_groups: FluidInteractionGroups,
+    /// Inserted as the [`FluidDebugColor`](crate::render::FluidDebugColor) of the fluid, unless
+    /// it already has one.
+    pub debug_color: Option<Color>,
+}
+
+impl Default for FluidMaterial {
+    fn default() -> Self {
+        Self {
+            density0: 1000.0,
+            nonpressure_forces: Vec::new(),
+            interaction_groups: FluidInteractionGroups::default(),
+            debug_color: None,
+        }
+    }
+}
+
+/// The [`FluidMaterial`] of a fluid entity.
+///
+/// The fluid is created once the material is loaded. Its [`FluidDensity`](crate::fluid::FluidDensity)
+/// and [`FluidInteractionGroups`] components take precedence over the material, and the forces of
+/// the material come before the ones of the entity.
+///
+/// When the material is modified (e.g. hot-reloaded) or the handle is replaced, the running
+/// fluid is updated in place without resetting its particles.
+#[derive(Component, Reflect, Clone, Debug, Default, PartialEq)]
+#[reflect(Component, Default)]
+pub struct FluidMaterialHandle(pub Handle<FluidMaterial>);
+
+/// What was applied to a fluid from its [`FluidMaterial`], so that the material can be applied
+/// again when it changes. Inserted automatically.
+#[derive(Component, Clone, Debug, Default)]
+pub struct AppliedFluidMaterial {
+    /// The number of non-pressure forces of the fluid that come from the material. They are
+    /// the first forces of the fluid.
+    pub(crate) nonpressure_force_count: usize,
+    /// The debug color given to the fluid by the material.
+    pub(crate) debug_color: Option<Color>,
+}
+
+/// Loads [`FluidMaterial`]s from `.fluid.ron` files.
+#[cfg(feature = "serialize")]
+#[derive(Default)]
+pub struct FluidMaterialLoader;
+
+/// An error while loading a [`FluidMaterial`].
+#[cfg(feature = "serialize")]
+#[derive(Debug)]
+pub enum FluidMaterialLoaderError {
+    Io(std::io::Error),
+    Ron(ron::de::SpannedError),
+}
+
+#[cfg(feature = "serialize")]
+impl std::fmt::Display for FluidMaterialLoaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "could not read fluid material: {err}"),
+            Self::Ron(err) => write!(f, "could not parse fluid material: {err}"),
+        }
+    }
+}
+
+#[cfg(feature = "serialize")]
+impl std::error::Error for FluidMaterialLoaderError {}
+
+#[cfg(feature = "serialize")]
+impl From<std::io::Error> for FluidMaterialLoaderError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+#[cfg(feature = "serialize")]
+impl From<ron::de::SpannedError> for FluidMaterialLoaderError {
+    fn from(err: ron::de::SpannedError) -> Self {
+        Self::Ron(err)
+    }
+}
+
+#[cfg(feature = "serialize")]
+impl AssetLoader for FluidMaterialLoader {
+    type Asset = FluidMaterial;
+    type Settings = ();
+    type Error = FluidMaterialLoaderError;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        _load_context: &mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        Ok(ron::de::from_bytes(&bytes)?)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["fluid.ron"]
+    }
+}
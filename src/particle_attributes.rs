@@ -0,0 +1,223 @@
+use crate::diagnostics::SalvaStepStats;
+use crate::fluid::{FluidParticleEditLog, FluidPositions, SalvaFluidHandle};
+use crate::math::{Real, Vect};
+use crate::plugin::{systems, SalvaContext, SalvaContextEntityLink, SalvaSimulationSet};
+use bevy::ecs::intern::Interned;
+use bevy::ecs::schedule::ScheduleLabel;
+use bevy::prelude::*;
+use salva::kernel::{CubicSplineKernel, Kernel};
+use std::ops::{Add, Deref, DerefMut, Mul};
+
+/// A per-particle attribute channel of a fluid entity, holding one `T` per particle.
+///
+/// The channel is kept in sync with the particles of the fluid: values follow their particle
+/// when [`FluidParticleEdits`](crate::fluid::FluidParticleEdits) add, remove or reorder
+/// particles, and the channel is resized with default values if [`FluidPositions`] is replaced.
+/// Attribute types must be registered with
+/// [`SalvaParticleAttributeAppExt::add_fluid_particle_attribute`].
+#[derive(Component, Reflect, Clone, Debug, Default)]
+#[reflect(Component)]
+pub struct FluidParticleAttributes<T: Send + Sync + 'static> {
+    /// The value of each particle.
+    pub values: Vec<T>,
+    /// How fast values are blended with the values of neighbor particles, per second.
+    /// Diffusion is disabled if this is zero.
+    ///
+    /// Only used for [`FluidParticleColors`] and types registered with
+    /// [`SalvaParticleAttributeAppExt::add_diffusible_fluid_particle_attribute`].
+    pub diffusion_rate: Real,
+    #[reflect(ignore)]
+    synced_generation: u64,
+}
+
+impl<T: Send + Sync + 'static> FluidParticleAttributes<T> {
+    pub fn new(values: Vec<T>) -> Self {
+        Self {
+            values,
+            diffusion_rate: 0.0,
+            synced_generation: 0,
+        }
+    }
+
+    pub fn with_diffusion_rate(mut self, diffusion_rate: Real) -> Self {
+        self.diffusion_rate = diffusion_rate;
+        self
+    }
+}
+
+impl<T: Send + Sync + 'static> Deref for FluidParticleAttributes<T> {
+    type Target = Vec<T>;
+    fn deref(&self) -> &Self::Target { &self.values }
+}
+
+impl<T: Send + Sync + 'static> DerefMut for FluidParticleAttributes<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target { &mut self.values }
+}
+
+/// The color of each particle of a fluid, e.g. for dye.
+///
+/// Colors are mixed between neighbor particles during the simulation step by the
+/// [`ColorMixing`](crate::color_mixing::ColorMixing) force, at the rate given by
+/// [`FluidParticleAttributes::diffusion_rate`].
+pub type FluidParticleColors = FluidParticleAttributes<LinearRgba>;
+
+/// The temperature of each particle of a fluid. Diffusible.
+pub type FluidParticleTemperatures = FluidParticleAttributes<Real>;
+
+/// Extension trait registering the systems that maintain [`FluidParticleAttributes`] channels.
+pub trait SalvaParticleAttributeAppExt {
+    /// Keeps the [`FluidParticleAttributes<T>`] of fluids in sync with their particles.
+    ///
+    /// `schedule` must be the schedule the [`SalvaPhysicsPlugin`](crate::plugin::SalvaPhysicsPlugin)
+    /// runs in.
+    fn add_fluid_particle_attribute<T>(&mut self, schedule: impl ScheduleLabel) -> &mut Self
+    where
+        T: Clone + Default + Send + Sync + 'static;
+
+    /// Like [`Self::add_fluid_particle_attribute`], and also diffuses the values of each fluid
+    /// between neighbor particles according to [`FluidParticleAttributes::diffusion_rate`].
+    fn add_diffusible_fluid_particle_attribute<T>(&mut self, schedule: impl ScheduleLabel) -> &mut Self
+    where
+        T: Clone + Default + Send + Sync + 'static + Add<Output = T> + Mul<Real, Output = T>;
+}
+
+impl SalvaParticleAttributeAppExt for App {
+    fn add_fluid_particle_attribute<T>(&mut self, schedule: impl ScheduleLabel) -> &mut Self
+    where
+        T: Clone + Default + Send + Sync + 'static,
+    {
+        let schedule: Interned<dyn ScheduleLabel> = schedule.intern();
+        self.add_systems(
+            schedule,
+            sync_fluid_particle_attributes::<T>
+                .in_set(SalvaSimulationSet::SyncBackend)
+                .after(systems::apply_fluid_particle_edits),
+        )
+    }
+
+    fn add_diffusible_fluid_particle_attribute<T>(&mut self, schedule: impl ScheduleLabel) -> &mut Self
+    where
+        T: Clone + Default + Send + Sync + 'static + Add<Output = T> + Mul<Real, Output = T>,
+    {
+        let schedule: Interned<dyn ScheduleLabel> = schedule.intern();
+        self.add_fluid_particle_attribute::<T>(schedule).add_systems(
+            schedule,
+            diffuse_fluid_particle_attributes::<T>
+                .in_set(SalvaSimulationSet::Writeback)
+                .after(systems::writeback_particle_kinematics),
+        )
+    }
+}
+
+/// Replays the particle edits of each fluid on its [`FluidParticleAttributes<T>`], then makes
+/// sure it has one value per particle.
+pub fn sync_fluid_particle_attributes<T: Clone + Default + Send + Sync + 'static>(
+    mut fluids: Query<(
+        &mut FluidParticleAttributes<T>,
+        &FluidPositions,
+        Option<&FluidParticleEditLog>,
+    )>,
+) {
+    for (mut attributes, positions, log) in fluids.iter_mut() {
+        if let Some(log) = log {
+            if attributes.synced_generation != log.generation {
+                // Edits can only be replayed if none were missed.
+                if attributes.synced_generation + 1 == log.generation {
+                    for edit in log.edits.iter() {
+                        edit.apply_to(&mut attributes.values, |_| T::default());
+                    }
+                }
+                attributes.synced_generation = log.generation;
+            }
+        }
+
+        if attributes.values.len() != positions.len() {
+            attributes.values.resize(positions.len(), T::default());
+        }
+    }
+}
+
+/// Blends the [`FluidParticleAttributes<T>`] of each particle with the values of its neighbors
+/// in the same fluid, weighted by the SPH kernel.
+///
+/// Values are blended over the time the fluid's [`SalvaContext`] was just stepped by (see
+/// [`SalvaStepStats::simulated_time`]), so nothing diffuses during the ticks where it isn't
+/// stepped. Neighbors are found with the context's
+/// [particle index](SalvaContext::particle_index).
+pub fn diffuse_fluid_particle_attributes<T>(
+    mut fluids: Query<(
+        &mut FluidParticleAttributes<T>,
+        &FluidPositions,
+        &SalvaFluidHandle,
+        &SalvaContextEntityLink,
+    )>,
+    salva_contexts: Query<(&SalvaContext, &SalvaStepStats)>,
+) where
+    T: Clone + Default + Send + Sync + 'static + Add<Output = T> + Mul<Real, Output = T>,
+{
+    for (mut attributes, positions, handle, link) in fluids.iter_mut() {
+        if attributes.diffusion_rate <= 0.0 || attributes.values.len() != positions.len() {
+            continue;
+        }
+        let Ok((context, stats)) = salva_contexts.get(link.0) else {
+            continue;
+        };
+        if stats.simulated_time <= 0.0 {
+            continue;
+        }
+        let h = context.liquid_world.h();
+        let blend = (attributes.diffusion_rate * stats.simulated_time).min(1.0);
+        let index = context.particle_index();
+        let values = diffuse(positions, &attributes.values, h, blend, |pos| {
+            index
+                .fluid_particles_in_radius(pos, h)
+                .filter(|(_, (neighbor_handle, _))| *neighbor_handle == handle.0)
+                .map(|(neighbor_pos, (_, j))| (*neighbor_pos, *j))
+        });
+        attributes.values = values;
+    }
+}
+
+/// Blends each value with the kernel-weighted average of the values of its neighbors, by the
+/// factor `blend`.
+///
+/// `neighbors` yields the position and the index in `values` of the particles within
+/// `kernel_radius` of a position. Indices out of `values` are ignored.
+pub fn diffuse<T, I>(
+    positions: &[Vect],
+    values: &[T],
+    kernel_radius: Real,
+    blend: Real,
+    neighbors: impl Fn(Vect) -> I,
+) -> Vec<T>
+where
+    T: Clone + Add<Output = T> + Mul<Real, Output = T>,
+    I: Iterator<Item = (Vect, usize)>,
+{
+    positions
+        .iter()
+        .zip(values.iter())
+        .map(|(pos, value)| {
+            let mut total_weight = 0.0;
+            let mut average: Option<T> = None;
+            for (neighbor_pos, j) in neighbors(*pos) {
+                let Some(neighbor_value) = values.get(j) else {
+                    continue;
+                };
+                let weight = CubicSplineKernel::scalar_apply(neighbor_pos.distance(*pos), kernel_radius);
+                total_weight += weight;
+                let weighted = neighbor_value.clone() * weight;
+                average = Some(match average {
+                    Some(average) => average + weighted,
+                    None => weighted,
+                });
+            }
+            match average {
+                Some(average) if total_weight > 0.0 => {
+                    value.clone() * (1.0 - blend) + average * (blend / total_weight)
+                }
+                _ => value.clone(),
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,121 @@
+use crate::plugin::{DefaultSalvaContext, SalvaContext, SalvaContextEntityLink};
+use bevy::prelude::{Component, Entity, Event, EventWriter, Query, With};
+use bevy_rapier::geometry::RapierColliderHandle;
+use bevy_rapier::parry::math::Point;
+use bevy_rapier::parry::query::PointQuery;
+use bevy_rapier::plugin::RapierContextColliders;
+use bevy_rapier::prelude::RapierContextEntityLink;
+use crate::math::Vect;
+use std::collections::HashMap;
+
+/// Add this to an entity with a [`Collider`](bevy_rapier::prelude::Collider) to receive
+/// [`FluidContactStarted`] and [`FluidContactStopped`] events for it.
+///
+/// A fluid particle is considered touching the collider when its center is within one particle
+/// radius of the collider's shape. Colliders without this component are never tested.
+#[derive(Component, Default, Clone, Debug)]
+pub struct FluidContactEvents {
+    contacts: HashMap<Entity, usize>,
+}
+
+impl FluidContactEvents {
+    /// The fluid entities currently touching this collider, with the number of touching particles.
+    pub fn contacts(&self) -> impl Iterator<Item = (Entity, usize)> + '_ {
+        self.contacts.iter().map(|(e, n)| (*e, *n))
+    }
+
+    /// Is the given fluid entity currently touching this collider?
+    pub fn is_touching(&self, fluid_entity: Entity) -> bool {
+        self.contacts.contains_key(&fluid_entity)
+    }
+}
+
+/// Event sent when a fluid starts touching a collider with [`FluidContactEvents`].
+#[derive(Event, Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FluidContactStarted {
+    pub fluid_entity: Entity,
+    pub collider_entity: Entity,
+    /// The number of particles of the fluid touching the collider.
+    pub particle_count: usize,
+}
+
+/// Event sent when a fluid stops touching a collider with [`FluidContactEvents`].
+#[derive(Event, Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FluidContactStopped {
+    pub fluid_entity: Entity,
+    pub collider_entity: Entity,
+    /// The number of particles of the fluid that were touching the collider during the
+    /// last step where contact was detected.
+    pub particle_count: usize,
+}
+
+/// The system that detects contacts between fluids and colliders with [`FluidContactEvents`],
+/// sending [`FluidContactStarted`] and [`FluidContactStopped`] events.
+pub fn emit_fluid_contact_events(
+    mut colliders: Query<(
+        Entity,
+        &mut FluidContactEvents,
+        &RapierColliderHandle,
+        &RapierContextEntityLink,
+        Option<&SalvaContextEntityLink>,
+    )>,
+    salva_contexts: Query<&SalvaContext>,
+    q_default_context: Query<Entity, With<DefaultSalvaContext>>,
+    rapier_colliders: Query<&RapierContextColliders>,
+    mut started_events: EventWriter<FluidContactStarted>,
+    mut stopped_events: EventWriter<FluidContactStopped>,
+) {
+    for (collider_entity, mut events, co_handle, rapier_link, salva_link) in colliders.iter_mut() {
+        let Some(salva_context_entity) = salva_link
+            .map(|link| link.0)
+            .or_else(|| q_default_context.single().ok())
+        else {
+            continue;
+        };
+        let Ok(context) = salva_contexts.get(salva_context_entity) else {
+            continue;
+        };
+        let Some(co) = rapier_colliders
+            .get(rapier_link.0)
+            .ok()
+            .and_then(|colliders| colliders.colliders.get(co_handle.0))
+        else {
+            continue;
+        };
+
+        let radius = context.liquid_world.particle_radius();
+        let aabb = co.compute_aabb();
+        let mins = Vect::from(aabb.mins) - Vect::splat(radius);
+        let maxs = Vect::from(aabb.maxs) + Vect::splat(radius);
+
+        let mut contacts = HashMap::new();
+        for particle in context.particle_index().particles_in_aabb(mins, maxs) {
+            if co.shape().distance_to_point(co.position(), &Point::from(particle.position), true)
+                <= radius
+            {
+                *contacts.entry(particle.fluid_entity).or_insert(0) += 1;
+            }
+        }
+
+        for (fluid_entity, particle_count) in contacts.iter() {
+            if !events.contacts.contains_key(fluid_entity) {
+                started_events.write(FluidContactStarted {
+                    fluid_entity: *fluid_entity,
+                    collider_entity,
+                    particle_count: *particle_count,
+                });
+            }
+        }
+        for (fluid_entity, particle_count) in events.contacts.iter() {
+            if !contacts.contains_key(fluid_entity) {
+                stopped_events.write(FluidContactStopped {
+                    fluid_entity: *fluid_entity,
+                    collider_entity,
+                    particle_count: *particle_count,
+                });
+            }
+        }
+
+        events.contacts = contacts;
+    }
+}
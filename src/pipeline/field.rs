@@ -0,0 +1,102 @@
+use crate::fluid::FluidPressures;
+use crate::math::{Real, Vect};
+use crate::plugin::SalvaContext;
+use bevy::prelude::Entity;
+use salva::kernel::{CubicSplineKernel, Kernel};
+
+/// The fluid fields at a point, estimated with the SPH kernel over the particles around it.
+/// See [`SalvaContext::sample_field`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FluidFieldSample {
+    /// The density of the fluids and boundaries at the point, with the same SPH sum as
+    /// [`FluidDensities`](crate::fluid::FluidDensities).
+    pub density: Real,
+    /// The kernel-weighted average velocity of the particles around the point.
+    pub velocity: Vect,
+    /// An estimate of the pressure at the point, derived from [`Self::density`] as for
+    /// [`FluidPressures`] with its default stiffness.
+    pub pressure: Real,
+    /// The kernel-weighted standard deviation of the particle velocities around the point.
+    pub turbulence: Real,
+    /// The normalized density field `sum_j V_j W(|x - x_j|, h)`: close to 1.0 inside a fluid
+    /// and 0.0 away from it.
+    pub fraction: Real,
+    /// The fluid entities with particles around the point.
+    pub fluid_entities: Vec<Entity>,
+}
+
+impl FluidFieldSample {
+    /// Is the point inside a fluid, i.e. is [`Self::fraction`] at least 0.5?
+    pub fn is_inside(&self) -> bool {
+        self.fraction >= 0.5
+    }
+}
+
+impl SalvaContext {
+    /// Samples the fluid fields at `point`, from the particles around it found with the
+    /// [particle index](Self::particle_index) of this context.
+    ///
+    /// Particles of fluids without an entity are ignored.
+    pub fn sample_field(&self, point: Vect) -> FluidFieldSample {
+        let h = self.liquid_world.h();
+        let fluids = self.liquid_world.fluids();
+
+        let mut sample = FluidFieldSample::default();
+        let mut weighted_density0 = 0.0;
+        // (V_j W, v_j) of each neighbor, kept for the turbulence.
+        let mut weighted_velocities = Vec::new();
+
+        // The index is searched with a margin since particles moved a bit since it was built.
+        for particle in self.particle_index().particles_in_radius(point, h * 1.5) {
+            let Some(fluid) = self
+                .entity2fluid
+                .get(&particle.fluid_entity)
+                .and_then(|handle| fluids.get(*handle))
+            else {
+                continue;
+            };
+            // Particles may have been removed since the index was built.
+            let (Some(pos), Some(velocity), Some(volume)) = (
+                fluid.positions.get(particle.index),
+                fluid.velocities.get(particle.index),
+                fluid.volumes.get(particle.index),
+            ) else {
+                continue;
+            };
+            let weight = volume * CubicSplineKernel::scalar_apply(Vect::from(*pos).distance(point), h);
+            if weight <= 0.0 {
+                continue;
+            }
+            let velocity = Vect::from(*velocity);
+
+            sample.fraction += weight;
+            sample.density += weight * fluid.density0;
+            weighted_density0 += weight * fluid.density0;
+            sample.velocity += velocity * weight;
+            weighted_velocities.push((weight, velocity));
+            if !sample.fluid_entities.contains(&particle.fluid_entity) {
+                sample.fluid_entities.push(particle.fluid_entity);
+            }
+        }
+
+        if sample.fraction <= 0.0 {
+            return FluidFieldSample::default();
+        }
+
+        let density0 = weighted_density0 / sample.fraction;
+        for (pos, volume) in self.particle_index().boundary_samples_in_radius(point, h) {
+            sample.density += volume * density0 * CubicSplineKernel::scalar_apply(pos.distance(point), h);
+        }
+
+        sample.velocity /= sample.fraction;
+        let variance: Real = weighted_velocities
+            .iter()
+            .map(|(weight, velocity)| weight * velocity.distance_squared(sample.velocity))
+            .sum::<Real>()
+            / sample.fraction;
+        sample.turbulence = variance.sqrt();
+        sample.pressure =
+            FluidPressures::estimate(sample.density, density0, FluidPressures::default().stiffness);
+        sample
+    }
+}
@@ -0,0 +1,152 @@
+use crate::math::{Real, Vect};
+use crate::pipeline::{FluidParticleRef, SalvaParticleIndex};
+#[cfg(feature = "rapier")]
+use bevy_rapier::parry::math::Point;
+#[cfg(feature = "rapier")]
+use bevy_rapier::parry::query::PointQuery;
+#[cfg(feature = "rapier")]
+use bevy_rapier::rapier::geometry::Collider;
+
+/// How much fluid a region contains. See [`SalvaParticleIndex::fill_level_aabb`].
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct FluidFillLevel {
+    /// The number of particles inside the region.
+    pub particle_count: usize,
+    /// The volume of the fluid inside the region, from the rest volume of its particles.
+    pub submerged_volume: Real,
+    /// The fraction of the region's volume filled with fluid.
+    pub fill_fraction: Real,
+    /// The height the fluid would have if it were at rest, measured along the up direction from
+    /// the bottom of the region.
+    pub fill_height: Real,
+    /// The height of the fluid surface along the up direction, from the origin. Splashes above the
+    /// surface are ignored.
+    pub surface_height: Option<Real>,
+}
+
+/// The fraction of the particles, from the bottom, below the estimated surface.
+const SURFACE_PERCENTILE: Real = 0.95;
+
+impl SalvaParticleIndex {
+    /// Estimates how much fluid the given AABB contains. `up` is typically the opposite of the
+    /// gravity.
+    pub fn fill_level_aabb(&self, mins: Vect, maxs: Vect, up: Vect) -> FluidFillLevel {
+        let region_volume = (maxs - mins).max(Vect::ZERO).element_product();
+        self.fill_level(self.particles_in_aabb(mins, maxs), mins, maxs, region_volume, up)
+    }
+
+    /// Estimates how much fluid the given Rapier collider contains. `up` is typically the
+    /// opposite of the gravity.
+    #[cfg(feature = "rapier")]
+    pub fn fill_level_collider(&self, collider: &Collider, up: Vect) -> FluidFillLevel {
+        let aabb = collider.compute_aabb();
+        let (mins, maxs) = (Vect::from(aabb.mins), Vect::from(aabb.maxs));
+        let region_volume = collider.shape().mass_properties(1.0).mass();
+        let particles = self.particles_in_aabb(mins, maxs).filter(|particle| {
+            collider
+                .shape()
+                .contains_point(collider.position(), &Point::from(particle.position))
+        });
+        self.fill_level(particles, mins, maxs, region_volume, up)
+    }
+
+    fn fill_level(
+        &self,
+        particles: impl Iterator<Item = FluidParticleRef>,
+        mins: Vect,
+        maxs: Vect,
+        region_volume: Real,
+        up: Vect,
+    ) -> FluidFillLevel {
+        let up = up.normalize_or_zero();
+        let mut heights: Vec<Real> = particles.map(|particle| particle.position.dot(up)).collect();
+        if heights.is_empty() {
+            return FluidFillLevel::default();
+        }
+        heights.sort_by(Real::total_cmp);
+
+        // The range of heights covered by the region.
+        let bottom: Real = (0..up.to_array().len())
+            .map(|axis| (mins[axis] * up[axis]).min(maxs[axis] * up[axis]))
+            .sum();
+        let top: Real = (0..up.to_array().len())
+            .map(|axis| (mins[axis] * up[axis]).max(maxs[axis] * up[axis]))
+            .sum();
+
+        let submerged_volume = heights.len() as Real * self.particle_volume();
+        let cross_section = if top > bottom { region_volume / (top - bottom) } else { 0.0 };
+        let fill_height = if cross_section > 0.0 {
+            (submerged_volume / cross_section).min(top - bottom)
+        } else {
+            0.0
+        };
+        let surface = heights[((heights.len() - 1) as Real * SURFACE_PERCENTILE) as usize];
+
+        FluidFillLevel {
+            particle_count: heights.len(),
+            submerged_volume,
+            fill_fraction: if region_volume > 0.0 {
+                (submerged_volume / region_volume).min(1.0)
+            } else {
+                0.0
+            },
+            fill_height,
+            surface_height: Some((surface + self.particle_radius()).min(top)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A column of ten particles in the unit box, from `y = 0.05` to `y = 0.95`, and one above it.
+    fn column(particle_radius: Real) -> SalvaParticleIndex {
+        let mut positions: Vec<Vect> = (0..10)
+            .map(|i| Vect::splat(0.5).with_y(0.05 + 0.1 * i as Real))
+            .collect();
+        positions.push(Vect::splat(0.5).with_y(2.0));
+        SalvaParticleIndex::from_positions(&positions, particle_radius)
+    }
+
+    fn assert_approx(a: Real, b: Real) {
+        assert!((a - b).abs() < 1.0e-5, "{a} != {b}");
+    }
+
+    #[test]
+    fn empty_regions_have_no_fluid() {
+        let fill = column(0.05).fill_level_aabb(Vect::splat(5.0), Vect::splat(6.0), Vect::Y);
+        assert_eq!(fill, FluidFillLevel::default());
+    }
+
+    #[test]
+    fn fill_level_of_a_partially_filled_box() {
+        let index = column(0.05);
+        let volume = 10.0 * index.particle_volume();
+
+        let fill = index.fill_level_aabb(Vect::ZERO, Vect::ONE, Vect::Y);
+        assert_eq!(fill.particle_count, 10);
+        assert_approx(fill.submerged_volume, volume);
+        assert_approx(fill.fill_fraction, volume);
+        assert_approx(fill.fill_height, volume);
+        // The 95th percentile particle, plus the particle radius.
+        assert_approx(fill.surface_height.unwrap(), 0.9);
+
+        // Heights are measured along the up direction.
+        let fill = index.fill_level_aabb(Vect::ZERO, Vect::ONE, -Vect::Y * 2.0);
+        assert_eq!(fill.particle_count, 10);
+        assert_approx(fill.fill_height, volume);
+        assert_approx(fill.surface_height.unwrap(), -0.1);
+    }
+
+    #[test]
+    fn fill_level_is_clamped_to_the_region() {
+        let index = column(0.3);
+        assert!(10.0 * index.particle_volume() > 1.0);
+
+        let fill = index.fill_level_aabb(Vect::ZERO, Vect::ONE, Vect::Y);
+        assert_approx(fill.fill_fraction, 1.0);
+        assert_approx(fill.fill_height, 1.0);
+        assert_approx(fill.surface_height.unwrap(), 1.0);
+    }
+}
@@ -0,0 +1,13 @@
+#[cfg(feature = "rapier")]
+pub use self::events::*;
+pub use self::field::*;
+pub use self::fill_level::*;
+pub use self::query::*;
+pub use self::sensor::*;
+
+#[cfg(feature = "rapier")]
+pub mod events;
+pub mod field;
+pub mod fill_level;
+pub mod query;
+pub mod sensor;
@@ -0,0 +1,478 @@
+use crate::math::{Real, Vect};
+use crate::plugin::{DefaultSalvaContext, SalvaContext};
+use crate::utils::{self, ParticleGrid};
+use bevy::ecs::system::SystemParam;
+use crate::diagnostics::SalvaStepStats;
+use bevy::prelude::{Entity, Query, With};
+use salva::kernel::{CubicSplineKernel, Kernel};
+use salva::object::FluidHandle;
+use salva::LiquidWorld;
+use std::collections::HashMap;
+
+/// A fluid particle found by a spatial query.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FluidParticleRef {
+    /// The fluid entity the particle belongs to.
+    pub fluid_entity: Entity,
+    /// The index of the particle in the fluid.
+    pub index: usize,
+    pub position: Vect,
+}
+
+/// What [`SalvaParticleIndex::ray_cast`] hits.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FluidRayTarget {
+    /// The first particle, seen as a ball of the particle radius.
+    Particles,
+    /// The iso-surface of the normalized density field `sum_j V_j W(|x - x_j|, h)`, which is
+    /// close to 1.0 inside the fluid.
+    Surface { iso_level: Real },
+}
+
+/// The result of [`SalvaParticleIndex::ray_cast`].
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FluidRayHit {
+    /// The distance from the ray origin to the hit point.
+    pub toi: Real,
+    pub point: Vect,
+    /// The outward normal of the particle or surface at the hit point.
+    pub normal: Vect,
+    /// The particle hit, or the particle closest to the surface hit.
+    pub particle: Option<FluidParticleRef>,
+}
+
+/// An acceleration structure over the fluid particles of a [`SalvaContext`], rebuilt right after
+/// every simulation step, and before the step when particles were added or removed. See
+/// [`SalvaContext::particle_index`] and [`SalvaQuery`].
+///
+/// Between two steps, the indexed positions are the ones at the end of the last step: particles
+/// moved by hand since then are found at their former position.
+#[derive(Default)]
+pub struct SalvaParticleIndex {
+    /// Every fluid particle, keyed by fluid handle and particle index.
+    grid: Option<ParticleGrid<(FluidHandle, usize)>>,
+    fluid2entity: HashMap<FluidHandle, Entity>,
+    /// The number of particles of each indexed fluid.
+    particle_counts: HashMap<FluidHandle, usize>,
+    /// The boundary samples, with their volume.
+    boundary_grid: Option<ParticleGrid<Real>>,
+    mins: Vect,
+    maxs: Vect,
+    particle_radius: Real,
+    particle_volume: Real,
+    kernel_radius: Real,
+}
+
+impl SalvaParticleIndex {
+    /// Indexes the fluid particles and boundary samples of `context`.
+    ///
+    /// Every fluid particle is indexed, but the queries returning [`FluidParticleRef`]s skip the
+    /// particles of fluids without an entity.
+    pub fn new(context: &SalvaContext) -> Self {
+        let kernel_radius = context.liquid_world.h();
+        let particle_radius = context.liquid_world.particle_radius();
+        let mut grid = ParticleGrid::new(kernel_radius);
+        let mut particle_counts = HashMap::new();
+        let mut mins = Vect::splat(Real::MAX);
+        let mut maxs = Vect::splat(Real::MIN);
+
+        for (handle, fluid) in context.liquid_world.fluids().iter() {
+            for (i, pos) in fluid.positions.iter().enumerate() {
+                let pos = Vect::from(*pos);
+                grid.insert(pos, (handle, i));
+                mins = mins.min(pos);
+                maxs = maxs.max(pos);
+            }
+            particle_counts.insert(handle, fluid.num_particles());
+        }
+
+        let mut boundary_grid = ParticleGrid::new(kernel_radius);
+        for (_, boundary) in context.liquid_world.boundaries().iter() {
+            for (pos, volume) in boundary.positions.iter().zip(boundary.volumes.iter()) {
+                boundary_grid.insert(Vect::from(*pos), *volume);
+            }
+        }
+
+        Self {
+            grid: (!grid.is_empty()).then_some(grid),
+            fluid2entity: context.fluid2entity(),
+            particle_counts,
+            boundary_grid: (!boundary_grid.is_empty()).then_some(boundary_grid),
+            mins,
+            maxs,
+            particle_radius,
+            particle_volume: utils::particle_volume(particle_radius),
+            kernel_radius,
+        }
+    }
+
+    /// Indexes particles of a single fluid entity at the given positions, in a context with a
+    /// kernel radius of `4 * particle_radius`.
+    #[cfg(test)]
+    pub(crate) fn from_positions(positions: &[Vect], particle_radius: Real) -> Self {
+        use salva::math::Point;
+        use salva::object::{interaction_groups::InteractionGroups, Fluid};
+        use salva::solver::DFSPHSolver;
+
+        let solver: DFSPHSolver = DFSPHSolver::new();
+        let mut context = SalvaContext::new(LiquidWorld::new(solver, particle_radius, 2.0));
+        let fluid = Fluid::new(
+            positions.iter().map(|pos| Point::from(*pos)).collect(),
+            particle_radius,
+            1000.0,
+            InteractionGroups::default(),
+        );
+        let handle = context.liquid_world.add_fluid(fluid);
+        context.entity2fluid.insert(Entity::from_raw(1), handle);
+        Self::new(&context)
+    }
+
+    /// Are the fluids of `liquid_world`, or their particle counts, different from the indexed
+    /// ones?
+    pub(crate) fn is_outdated(&self, liquid_world: &LiquidWorld) -> bool {
+        let fluids = liquid_world.fluids();
+        fluids.iter().count() != self.particle_counts.len()
+            || fluids.iter().any(|(handle, fluid)| {
+                self.particle_counts.get(&handle) != Some(&fluid.num_particles())
+            })
+    }
+
+    /// The radius of the indexed particles.
+    pub fn particle_radius(&self) -> Real {
+        self.particle_radius
+    }
+
+    /// The rest volume of each indexed particle.
+    pub fn particle_volume(&self) -> Real {
+        self.particle_volume
+    }
+
+    /// Iterates over the particles whose center lies inside the given AABB.
+    pub fn particles_in_aabb(&self, mins: Vect, maxs: Vect) -> impl Iterator<Item = FluidParticleRef> + '_ {
+        self.fluid_particles_in_aabb(mins, maxs)
+            .filter_map(|entry| self.particle_ref(entry))
+    }
+
+    /// Iterates over the particles whose center lies within `radius` of `center`.
+    pub fn particles_in_radius(&self, center: Vect, radius: Real) -> impl Iterator<Item = FluidParticleRef> + '_ {
+        self.fluid_particles_in_radius(center, radius)
+            .filter_map(|entry| self.particle_ref(entry))
+    }
+
+    /// Iterates over the position, fluid handle and index of the particles whose center lies
+    /// inside the given AABB, including the particles of fluids without an entity.
+    pub fn fluid_particles_in_aabb(
+        &self,
+        mins: Vect,
+        maxs: Vect,
+    ) -> impl Iterator<Item = &(Vect, (FluidHandle, usize))> + '_ {
+        self.grid.iter().flat_map(move |grid| grid.in_aabb(mins, maxs))
+    }
+
+    /// Iterates over the position, fluid handle and index of the particles whose center lies
+    /// within `radius` of `center`, including the particles of fluids without an entity.
+    pub fn fluid_particles_in_radius(
+        &self,
+        center: Vect,
+        radius: Real,
+    ) -> impl Iterator<Item = &(Vect, (FluidHandle, usize))> + '_ {
+        self.grid.iter().flat_map(move |grid| grid.in_radius(center, radius))
+    }
+
+    /// Iterates over the positions and volumes of the boundary samples within `radius` of
+    /// `center`.
+    pub fn boundary_samples_in_radius(&self, center: Vect, radius: Real) -> impl Iterator<Item = (Vect, Real)> + '_ {
+        self.boundary_grid
+            .iter()
+            .flat_map(move |grid| grid.in_radius(center, radius))
+            .map(|(pos, volume)| (*pos, *volume))
+    }
+
+    /// The particle closest to `point`, if there is any particle within `max_distance`.
+    pub fn nearest_particle(&self, point: Vect, max_distance: Real) -> Option<FluidParticleRef> {
+        let grid = self.grid.as_ref()?;
+        // No particle is further than the farthest corner of the particles' AABB.
+        let max_distance = max_distance.min(
+            (point - self.mins).abs().max((point - self.maxs).abs()).length()
+        );
+
+        // Search in growing balls until a particle is found.
+        let mut radius = grid.cell_size();
+        loop {
+            let radius_clamped = radius.min(max_distance);
+            let nearest = grid
+                .in_radius(point, radius_clamped)
+                .filter_map(|entry| self.particle_ref(entry))
+                .min_by(|a, b| {
+                    a.position.distance_squared(point).total_cmp(&b.position.distance_squared(point))
+                });
+            if nearest.is_some() {
+                return nearest;
+            }
+            if radius >= max_distance {
+                return None;
+            }
+            radius *= 2.0;
+        }
+    }
+
+    /// The normalized density field `sum_j V_j W(|x - x_j|, h)` at `point`.
+    pub fn density_field(&self, point: Vect) -> Real {
+        let Some(grid) = &self.grid else {
+            return 0.0;
+        };
+        grid.in_radius(point, self.kernel_radius)
+            .map(|(pos, _)| {
+                self.particle_volume * CubicSplineKernel::scalar_apply(pos.distance(point), self.kernel_radius)
+            })
+            .sum()
+    }
+
+    /// Casts a ray against the fluid, up to a distance of `max_toi`. `dir` doesn't need to be
+    /// normalized.
+    pub fn ray_cast(&self, origin: Vect, dir: Vect, max_toi: Real, target: FluidRayTarget) -> Option<FluidRayHit> {
+        let grid = self.grid.as_ref()?;
+        let dir = dir.try_normalize()?;
+
+        // Only march through the AABB of the particles and their influence.
+        let margin = Vect::splat(self.kernel_radius.max(self.particle_radius));
+        let (t_min, t_max) = ray_aabb(origin, dir, self.mins - margin, self.maxs + margin)?;
+        let t_max = t_max.min(max_toi);
+        let t_min = t_min.max(0.0);
+        if t_min > t_max {
+            return None;
+        }
+
+        match target {
+            FluidRayTarget::Particles => {
+                let step = grid.cell_size();
+                let mut t = t_min;
+                while t <= t_max {
+                    // The ball around the middle of the segment [t, t + step], inflated by the
+                    // particle radius, contains every particle the segment may hit.
+                    let middle = origin + dir * (t + step / 2.0);
+                    let hit = grid
+                        .in_radius(middle, step / 2.0 + self.particle_radius)
+                        .filter_map(|entry| {
+                            let particle = self.particle_ref(entry)?;
+                            let toi = ray_ball(origin, dir, particle.position, self.particle_radius)?;
+                            Some((toi, particle))
+                        })
+                        .filter(|(toi, _)| *toi <= max_toi)
+                        .min_by(|(a, _), (b, _)| a.total_cmp(b));
+                    if let Some((toi, particle)) = hit.filter(|(toi, _)| *toi <= t + step) {
+                        let point = origin + dir * toi;
+                        return Some(FluidRayHit {
+                            toi,
+                            point,
+                            normal: (point - particle.position).normalize_or_zero(),
+                            particle: Some(particle),
+                        });
+                    }
+                    t += step;
+                }
+                None
+            }
+            FluidRayTarget::Surface { iso_level } => {
+                let step = self.particle_radius / 2.0;
+                let mut t = t_min;
+                let mut previous = self.density_field(origin + dir * t);
+                if previous >= iso_level {
+                    // The ray starts inside the fluid.
+                    let point = origin + dir * t;
+                    return Some(FluidRayHit {
+                        toi: t,
+                        point,
+                        normal: self.field_normal(point),
+                        particle: self.nearest_particle(point, self.kernel_radius),
+                    });
+                }
+                while t < t_max {
+                    let next_t = (t + step).min(t_max);
+                    let value = self.density_field(origin + dir * next_t);
+                    if value >= iso_level {
+                        // Interpolate the crossing linearly.
+                        let toi = t + (next_t - t) * (iso_level - previous) / (value - previous);
+                        let point = origin + dir * toi;
+                        return Some(FluidRayHit {
+                            toi,
+                            point,
+                            normal: self.field_normal(point),
+                            particle: self.nearest_particle(point, self.kernel_radius),
+                        });
+                    }
+                    previous = value;
+                    t = next_t;
+                }
+                None
+            }
+        }
+    }
+
+    /// The particle of an index entry, if its fluid has an entity.
+    fn particle_ref(&self, entry: &(Vect, (FluidHandle, usize))) -> Option<FluidParticleRef> {
+        let (position, (handle, index)) = *entry;
+        Some(FluidParticleRef {
+            fluid_entity: *self.fluid2entity.get(&handle)?,
+            index,
+            position,
+        })
+    }
+
+    /// The outward normal of the density field at `point`, from its gradient.
+    fn field_normal(&self, point: Vect) -> Vect {
+        let eps = self.particle_radius / 4.0;
+        let mut gradient = Vect::ZERO;
+        for axis in 0..gradient.to_array().len() {
+            let mut offset = Vect::ZERO;
+            offset[axis] = eps;
+            gradient[axis] = self.density_field(point + offset) - self.density_field(point - offset);
+        }
+        -gradient.normalize_or_zero()
+    }
+}
+
+/// A [`SystemParam`] for spatial queries over the fluid particles of salva contexts.
+#[derive(SystemParam)]
+pub struct SalvaQuery<'w, 's> {
+    contexts: Query<'w, 's, &'static SalvaContext>,
+    default_context: Query<'w, 's, &'static SalvaContext, With<DefaultSalvaContext>>,
+}
+
+impl SalvaQuery<'_, '_> {
+    /// The particle index of the given salva context entity.
+    pub fn context(&self, context: Entity) -> Option<&SalvaParticleIndex> {
+        self.contexts.get(context).ok().map(SalvaContext::particle_index)
+    }
+
+    /// The particle index of the default salva context.
+    pub fn default_context(&self) -> Option<&SalvaParticleIndex> {
+        self.default_context.single().ok().map(SalvaContext::particle_index)
+    }
+}
+
+/// Rebuilds the [`SalvaParticleIndex`] of the salva contexts that were stepped, or whose
+/// particles were added or removed. Runs right after the simulation steps.
+pub fn update_particle_indices(mut contexts: Query<(&mut SalvaContext, &SalvaStepStats)>) {
+    for (mut context, stats) in contexts.iter_mut() {
+        if stats.solver_steps > 0 || context.particle_index.is_outdated(&context.liquid_world) {
+            // Don't mark the context as changed.
+            let context = context.bypass_change_detection();
+            context.particle_index = SalvaParticleIndex::new(context);
+        }
+    }
+}
+
+/// Rebuilds the [`SalvaParticleIndex`] of the salva contexts whose particles were added or
+/// removed since the last step, so the systems running before the step don't use stale particle
+/// indices.
+pub fn refresh_outdated_particle_indices(mut contexts: Query<&mut SalvaContext>) {
+    for mut context in contexts.iter_mut() {
+        if context.particle_index.is_outdated(&context.liquid_world) {
+            let context = context.bypass_change_detection();
+            context.particle_index = SalvaParticleIndex::new(context);
+        }
+    }
+}
+
+/// The distance along the normalized `dir` at which the ray enters the ball, or 0.0 if it
+/// starts inside.
+fn ray_ball(origin: Vect, dir: Vect, center: Vect, radius: Real) -> Option<Real> {
+    let offset = origin - center;
+    let b = offset.dot(dir);
+    let c = offset.length_squared() - radius * radius;
+    if c <= 0.0 {
+        return Some(0.0);
+    }
+    let discriminant = b * b - c;
+    if b > 0.0 || discriminant < 0.0 {
+        return None;
+    }
+    Some(-b - discriminant.sqrt())
+}
+
+/// The range of distances along the normalized `dir` inside the AABB.
+fn ray_aabb(origin: Vect, dir: Vect, mins: Vect, maxs: Vect) -> Option<(Real, Real)> {
+    let mut t_min = Real::MIN;
+    let mut t_max = Real::MAX;
+    for axis in 0..origin.to_array().len() {
+        if dir[axis] == 0.0 {
+            if origin[axis] < mins[axis] || origin[axis] > maxs[axis] {
+                return None;
+            }
+            continue;
+        }
+        let t1 = (mins[axis] - origin[axis]) / dir[axis];
+        let t2 = (maxs[axis] - origin[axis]) / dir[axis];
+        t_min = t_min.max(t1.min(t2));
+        t_max = t_max.min(t1.max(t2));
+    }
+    (t_min <= t_max).then_some((t_min, t_max))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARTICLE_RADIUS: Real = 0.05;
+
+    fn assert_approx(a: Real, b: Real) {
+        assert!((a - b).abs() < 1.0e-5, "{a} != {b}");
+    }
+
+    #[test]
+    fn ray_ball_hits() {
+        assert_approx(ray_ball(Vect::ZERO, Vect::X, Vect::X * 3.0, 1.0).unwrap(), 2.0);
+        // Starting inside.
+        assert_eq!(ray_ball(Vect::X * 2.5, Vect::X, Vect::X * 3.0, 1.0), Some(0.0));
+        // Behind the origin.
+        assert_eq!(ray_ball(Vect::X * 5.0, Vect::X, Vect::X * 3.0, 1.0), None);
+        // Passing by.
+        assert_eq!(ray_ball(Vect::Y * 2.0, Vect::X, Vect::X * 3.0, 1.0), None);
+    }
+
+    #[test]
+    fn ray_aabb_ranges() {
+        let (mins, maxs) = (Vect::ZERO, Vect::ONE);
+        let origin = -Vect::X + Vect::Y * 0.5;
+        let (t_min, t_max) = ray_aabb(origin, Vect::X, mins, maxs).unwrap();
+        assert_approx(t_min, 1.0);
+        assert_approx(t_max, 2.0);
+        // Starting inside.
+        let (t_min, t_max) = ray_aabb(Vect::splat(0.5), Vect::X, mins, maxs).unwrap();
+        assert_approx(t_min, -0.5);
+        assert_approx(t_max, 0.5);
+        // Parallel to a face, outside of the box.
+        assert_eq!(ray_aabb(-Vect::X + Vect::Y * 2.0, Vect::X, mins, maxs), None);
+        // Pointing away.
+        let (_, t_max) = ray_aabb(origin, -Vect::X, mins, maxs).unwrap();
+        assert!(t_max < 0.0);
+    }
+
+    #[test]
+    fn nearest_particle_searches_beyond_the_first_cell() {
+        let index = SalvaParticleIndex::from_positions(&[Vect::ZERO, Vect::X, Vect::X * 3.0], PARTICLE_RADIUS);
+
+        let nearest = index.nearest_particle(Vect::X * 2.3, Real::INFINITY).unwrap();
+        assert_eq!(nearest.index, 2);
+        let nearest = index.nearest_particle(Vect::X * 100.0, Real::INFINITY).unwrap();
+        assert_eq!(nearest.index, 2);
+        assert_eq!(index.nearest_particle(Vect::X * 2.3, 0.5), None);
+        assert_eq!(SalvaParticleIndex::default().nearest_particle(Vect::ZERO, 1.0), None);
+    }
+
+    #[test]
+    fn ray_casts_hit_the_first_particle() {
+        let index = SalvaParticleIndex::from_positions(&[Vect::ZERO, Vect::X], PARTICLE_RADIUS);
+
+        let hit = index
+            .ray_cast(-Vect::X, Vect::X * 2.0, 10.0, FluidRayTarget::Particles)
+            .unwrap();
+        assert_approx(hit.toi, 1.0 - PARTICLE_RADIUS);
+        assert_eq!(hit.particle.unwrap().index, 0);
+        assert!((hit.normal + Vect::X).length() < 1.0e-5);
+
+        assert_eq!(index.ray_cast(-Vect::X, Vect::X, 0.5, FluidRayTarget::Particles), None);
+        assert_eq!(index.ray_cast(-Vect::X, -Vect::X, 10.0, FluidRayTarget::Particles), None);
+    }
+}
@@ -0,0 +1,161 @@
+use crate::math::{Real, Vect};
+use crate::plugin::{DefaultSalvaContext, SalvaContext, SalvaContextEntityLink};
+use bevy::prelude::{Component, Entity, GlobalTransform, Query, Reflect, With};
+#[cfg(feature = "rapier")]
+use bevy_rapier::geometry::RapierColliderHandle;
+#[cfg(feature = "rapier")]
+use bevy_rapier::parry::math::Point;
+#[cfg(feature = "rapier")]
+use bevy_rapier::parry::query::PointQuery;
+#[cfg(feature = "rapier")]
+use bevy_rapier::plugin::RapierContextColliders;
+#[cfg(feature = "rapier")]
+use bevy_rapier::prelude::RapierContextEntityLink;
+use std::collections::HashMap;
+
+/// A volume that reports the fluid it contains every step in its [`FluidSensorReadings`].
+///
+/// The volume is placed at the entity's [`GlobalTransform`] translation.
+#[derive(Component, Clone, Debug, PartialEq, Reflect)]
+#[require(FluidSensorReadings)]
+pub enum FluidSensor {
+    /// A world-space axis-aligned box with the given half-extents.
+    Aabb { half_extents: Vect },
+    /// A ball with the given radius.
+    Ball { radius: Real },
+    /// The shape of the Rapier [`Collider`](bevy_rapier::prelude::Collider) on the same entity.
+    ///
+    /// The collider is typically marked as a [`Sensor`](bevy_rapier::prelude::Sensor).
+    #[cfg(feature = "rapier")]
+    Collider,
+}
+
+/// The fluid contained in a [`FluidSensor`] for a single fluid entity.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Reflect)]
+pub struct FluidOccupancy {
+    /// The number of particles inside the sensor.
+    pub particle_count: usize,
+    /// The total mass of the particles inside the sensor.
+    pub mass: Real,
+    /// The average velocity of the particles inside the sensor.
+    pub average_velocity: Vect,
+}
+
+/// The readings of a [`FluidSensor`], updated after each simulation step.
+#[derive(Component, Clone, Debug, Default)]
+pub struct FluidSensorReadings(pub HashMap<Entity, FluidOccupancy>);
+
+impl FluidSensorReadings {
+    /// The occupancy of the given fluid entity, if any of its particles is inside the sensor.
+    pub fn get(&self, fluid_entity: Entity) -> Option<&FluidOccupancy> {
+        self.0.get(&fluid_entity)
+    }
+
+    /// Is the sensor free of any fluid?
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The combined occupancy of every fluid inside the sensor.
+    /// The average velocity is weighted by particle count.
+    pub fn total(&self) -> FluidOccupancy {
+        let mut total = FluidOccupancy::default();
+        for occupancy in self.0.values() {
+            total.particle_count += occupancy.particle_count;
+            total.mass += occupancy.mass;
+            total.average_velocity += occupancy.average_velocity * occupancy.particle_count as Real;
+        }
+        if total.particle_count > 0 {
+            total.average_velocity /= total.particle_count as Real;
+        }
+        total
+    }
+}
+
+/// The system that updates the [`FluidSensorReadings`] of every [`FluidSensor`].
+#[cfg_attr(not(feature = "rapier"), allow(unused_variables))]
+pub fn update_fluid_sensors(
+    mut sensors: Query<(
+        Entity,
+        &FluidSensor,
+        &mut FluidSensorReadings,
+        &GlobalTransform,
+        Option<&SalvaContextEntityLink>,
+    )>,
+    salva_contexts: Query<&SalvaContext>,
+    q_default_context: Query<Entity, With<DefaultSalvaContext>>,
+    #[cfg(feature = "rapier")]
+    sensor_colliders: Query<(&RapierColliderHandle, &RapierContextEntityLink)>,
+    #[cfg(feature = "rapier")]
+    rapier_colliders: Query<&RapierContextColliders>,
+) {
+    for (entity, sensor, mut readings, transform, salva_link) in sensors.iter_mut() {
+        readings.0.clear();
+
+        let Some(salva_context_entity) = salva_link
+            .map(|link| link.0)
+            .or_else(|| q_default_context.single().ok())
+        else {
+            continue;
+        };
+        let Ok(context) = salva_contexts.get(salva_context_entity) else {
+            continue;
+        };
+        let index = context.particle_index();
+
+        #[cfg(feature = "dim2")]
+        let center = transform.translation().truncate();
+        #[cfg(feature = "dim3")]
+        let center = transform.translation();
+
+        let candidates: Box<dyn Iterator<Item = _>> = match sensor {
+            FluidSensor::Aabb { half_extents } => {
+                Box::new(index.particles_in_aabb(center - *half_extents, center + *half_extents))
+            }
+            FluidSensor::Ball { radius } => Box::new(index.particles_in_radius(center, *radius)),
+            #[cfg(feature = "rapier")]
+            FluidSensor::Collider => {
+                let Some(co) = sensor_colliders.get(entity).ok().and_then(|(co_handle, rapier_link)| {
+                    rapier_colliders
+                        .get(rapier_link.0)
+                        .ok()?
+                        .colliders
+                        .get(co_handle.0)
+                }) else {
+                    continue;
+                };
+                let aabb = co.compute_aabb();
+                Box::new(
+                    index
+                        .particles_in_aabb(Vect::from(aabb.mins), Vect::from(aabb.maxs))
+                        .filter(move |particle| {
+                            let point = Point::from(particle.position);
+                            co.shape().contains_point(co.position(), &point)
+                        }),
+                )
+            }
+        };
+
+        for particle in candidates {
+            let Some(fluid) = context
+                .entity2fluid
+                .get(&particle.fluid_entity)
+                .and_then(|handle| context.liquid_world.fluids().get(*handle))
+            else {
+                continue;
+            };
+            let i = particle.index;
+            if i >= fluid.num_particles() {
+                continue;
+            }
+            let occupancy = readings.0.entry(particle.fluid_entity).or_default();
+            occupancy.particle_count += 1;
+            occupancy.mass += fluid.volumes[i] * fluid.density0;
+            occupancy.average_velocity += Vect::from(fluid.velocities[i]);
+        }
+
+        for occupancy in readings.0.values_mut() {
+            occupancy.average_velocity /= occupancy.particle_count as Real;
+        }
+    }
+}
@@ -1,5 +1,5 @@
-use crate::math::Vect;
-use bevy::prelude::{Component, Resource};
+use crate::math::{Real, Vect};
+use bevy::prelude::{Component, Resource, Time};
 
 /// This structure is used when [`TimestepMode::Interpolated`] is
 /// enabled for a [`SalvaContext`] entity.
@@ -40,6 +40,52 @@ pub enum TimestepMode {
     },
 }
 
+impl TimestepMode {
+    /// The total amount of simulated time the physics simulation advances by during a Bevy tick
+    /// where `time` elapsed.
+    ///
+    /// For [`TimestepMode::Interpolated`], this is the scaled real time, which the simulation
+    /// catches up with on average.
+    pub fn delta(&self, time: &Time) -> Real {
+        match *self {
+            TimestepMode::Fixed { dt, .. } => dt,
+            TimestepMode::Variable {
+                max_dt, time_scale, ..
+            } => (time.delta_secs() * time_scale).min(max_dt),
+            TimestepMode::Interpolated { time_scale, .. } => time.delta_secs() * time_scale,
+        }
+    }
+
+    /// The amount of simulated time a [`SalvaContext`](crate::plugin::SalvaContext) advances by
+    /// when it is stepped during a Bevy tick where `time` elapsed.
+    ///
+    /// Unlike [`Self::delta`], this is zero with [`TimestepMode::Interpolated`] if the simulation
+    /// is ahead of the real time and won't be stepped during this tick.
+    pub fn step_delta(&self, time: &Time, sim_to_render_time: &SimulationToRenderTime) -> Real {
+        match *self {
+            TimestepMode::Interpolated {
+                dt,
+                time_scale,
+                substeps,
+            } => {
+                // Mirrors the stepping loop of `SalvaContext::step_simulation`.
+                let substep_dt = (dt / substeps as Real) * time_scale;
+                if substep_dt <= 0.0 {
+                    return 0.0;
+                }
+                let mut diff = sim_to_render_time.diff + time.delta_secs();
+                let mut delta = 0.0;
+                while diff > 0.0 {
+                    delta += substep_dt * substeps as Real;
+                    diff -= substep_dt;
+                }
+                delta
+            }
+            _ => self.delta(time),
+        }
+    }
+}
+
 impl Default for TimestepMode {
     fn default() -> Self {
         TimestepMode::Variable {
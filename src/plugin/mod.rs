@@ -1,14 +1,16 @@
 pub use self::plugin::{
-    SalvaContextInitialization, SalvaPhysicsPlugin, SalvaSimulationSet
+    SalvaContextInitialization, SalvaPhysicsHooks, SalvaPhysicsPlugin, SalvaSimulationSet
 };
 pub use crate::fluid::AppendNonPressureForces;
 pub use crate::fluid::RemoveNonPressureForcesAt;
 pub use configuration::*;
 pub use salva_context::*;
+pub use snapshot::*;
 
 #[allow(clippy::type_complexity)]
 pub mod systems;
 #[allow(clippy::module_inception)]
 mod plugin;
 mod salva_context;
-mod configuration;
\ No newline at end of file
+mod configuration;
+mod snapshot;
\ No newline at end of file
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::marker::PhantomData;
 
 use crate::math::Real;
 use crate::plugin::salva_context::SalvaContext;
@@ -18,21 +18,73 @@ use bevy::{
 use salva::solver::DFSPHSolver;
 use salva::LiquidWorld;
 
+use crate::diagnostics;
+use crate::export;
+use crate::fluid;
+use crate::force_field;
+use crate::material;
+use crate::recording;
+use crate::color_mixing;
+use crate::particle_attributes::{self, SalvaParticleAttributeAppExt};
+use crate::pipeline;
 #[cfg(feature = "rapier")]
 use crate::rapier_integration;
 #[cfg(feature = "rapier")]
-use bevy::app::PostStartup;
+use bevy::ecs::system::SystemParamItem;
 #[cfg(feature = "rapier")]
-use bevy_rapier::plugin::PhysicsSet;
+use bevy_rapier::plugin::{PhysicsSet, RapierContextInitialization};
 #[cfg(feature = "rapier")]
-use bevy_rapier::prelude::RapierPhysicsPlugin;
+use bevy_rapier::prelude::{BevyPhysicsHooks, RapierPhysicsPlugin};
 
 use super::SalvaConfiguration;
 
-pub struct SalvaPhysicsPlugin {
+/// The type parameter of [`SalvaPhysicsPlugin`], matching the `PhysicsHooks` type parameter
+/// of the [`RapierPhysicsPlugin`] used by the app.
+///
+/// Implemented for every type that can be used as Rapier physics hooks.
+#[cfg(feature = "rapier")]
+pub trait SalvaPhysicsHooks: 'static + Send + Sync {
+    /// Adds the [`RapierPhysicsPlugin`] with these hooks, unless a [`RapierPhysicsPlugin`] was
+    /// already added, whatever its hooks.
+    ///
+    /// Called from [`Plugin::finish`], once every plugin of the app is built.
+    fn ensure_rapier_plugin(app: &mut App);
+}
+
+#[cfg(feature = "rapier")]
+impl<PhysicsHooks> SalvaPhysicsHooks for PhysicsHooks
+where
+    PhysicsHooks: 'static + BevyPhysicsHooks,
+    for<'w, 's> SystemParamItem<'w, 's, PhysicsHooks>: BevyPhysicsHooks,
+{
+    fn ensure_rapier_plugin(app: &mut App) {
+        // Every `RapierPhysicsPlugin`, whatever its hooks, inserts this resource when built.
+        let rapier_added = app.world().contains_resource::<RapierContextInitialization>()
+            || app.is_plugin_added::<RapierPhysicsPlugin<PhysicsHooks>>();
+        if !rapier_added {
+            app.add_plugins(RapierPhysicsPlugin::<PhysicsHooks>::default());
+        }
+    }
+}
+
+/// The type parameter of [`SalvaPhysicsPlugin`]. Without the `rapier` feature, it is unused.
+#[cfg(not(feature = "rapier"))]
+pub trait SalvaPhysicsHooks: 'static + Send + Sync {}
+
+#[cfg(not(feature = "rapier"))]
+impl<PhysicsHooks: 'static + Send + Sync> SalvaPhysicsHooks for PhysicsHooks {}
+
+/// The plugin that sets up the Salva fluid simulation.
+///
+/// With the `rapier` feature, a [`RapierPhysicsPlugin<PhysicsHooks>`] is added when the app is
+/// finished if no [`RapierPhysicsPlugin`] was added, with any hooks. Your own
+/// [`RapierPhysicsPlugin`] can be added before or after this plugin, or use
+/// [`SalvaPhysicsPlugin::with_physics_hooks`] to choose the hooks of the added one.
+pub struct SalvaPhysicsPlugin<PhysicsHooks = ()> {
     schedule: Interned<dyn ScheduleLabel>,
     default_system_setup: bool,
     world_setup: SalvaContextInitialization,
+    _phantom: PhantomData<fn() -> PhysicsHooks>,
 }
 
 impl SalvaPhysicsPlugin {
@@ -46,34 +98,26 @@ impl SalvaPhysicsPlugin {
             world_setup: SalvaContextInitialization::InitializeDefaultSalvaContext {
                 particle_radius: Self::DEFAULT_PARTICLE_RADIUS,
                 smoothing_factor: Self::DEFAULT_SMOOTHING_FACTOR
-            }
+            },
+            _phantom: PhantomData,
         }
     }
 
-    pub fn in_schedule(mut self, schedule: impl ScheduleLabel) -> Self {
-        self.schedule = schedule.intern();
-        self
-    }
-
-    pub fn with_custom_world_initialization(mut self, world_setup: SalvaContextInitialization) -> Self {
-        self.world_setup = world_setup;
-        self
-    }
-
-    pub fn with_default_system_setup(mut self, use_default_coupling: bool) -> Self {
-        self.default_system_setup = use_default_coupling;
-        self
-    }
-
     pub fn get_systems(set: SalvaSimulationSet) ->  ScheduleConfigs<ScheduleSystem> {
         #[cfg(feature = "rapier")]
         match set {
             SalvaSimulationSet::SyncBackend => {
                 (
                     systems::sync_removals,
+                    systems::sync_restored_fluids,
                     systems::init_fluids,
                     systems::apply_fluid_user_changes,
+                    systems::apply_fluid_particle_edits,
+                    rapier_integration::link_default_contexts,
+                    rapier_integration::couple_rapier_contexts,
+                    rapier_integration::sync_rapier_gravity,
                     rapier_integration::sample_rapier_colliders,
+                    pipeline::refresh_outdated_particle_indices,
                 )
                     .chain()
                     .in_set(SalvaSimulationSet::SyncBackend)
@@ -81,14 +125,24 @@ impl SalvaPhysicsPlugin {
             },
             SalvaSimulationSet::StepSimulation => {
                 (
+                    force_field::apply_fluid_impulses,
+                    force_field::apply_fluid_force_fields,
                     systems::step_simulation,
-                    rapier_integration::step_simulation_rapier_coupling
+                    rapier_integration::step_simulation_rapier_coupling,
+                    pipeline::update_particle_indices,
+                    rapier_integration::apply_analytic_buoyancy,
                 )
+                    .chain()
                     .in_set(SalvaSimulationSet::StepSimulation)
                     .after(PhysicsSet::StepSimulation)
             }
             SalvaSimulationSet::Writeback => {
-                (systems::writeback_particle_kinematics,)
+                (
+                    systems::writeback_particle_kinematics,
+                    systems::writeback_particle_densities,
+                    pipeline::emit_fluid_contact_events,
+                    pipeline::update_fluid_sensors,
+                )
                     .chain()
                     .in_set(SalvaSimulationSet::Writeback)
                     .after(PhysicsSet::Writeback)
@@ -99,35 +153,105 @@ impl SalvaPhysicsPlugin {
         match set {
             SalvaSimulationSet::SyncBackend => (
                 systems::sync_removals,
+                systems::sync_restored_fluids,
                 systems::init_fluids,
                 systems::apply_fluid_user_changes,
+                systems::apply_fluid_particle_edits,
+                pipeline::refresh_outdated_particle_indices,
             )
                 .chain()
                 .in_set(SalvaSimulationSet::SyncBackend),
-            SalvaSimulationSet::StepSimulation => {
-                (systems::step_simulation).in_set(SalvaSimulationSet::StepSimulation)
-            }
-            SalvaSimulationSet::Writeback => (systems::writeback_particle_kinematics,)
+            SalvaSimulationSet::StepSimulation => (
+                force_field::apply_fluid_impulses,
+                force_field::apply_fluid_force_fields,
+                systems::step_simulation,
+                pipeline::update_particle_indices,
+            )
+                .chain()
+                .in_set(SalvaSimulationSet::StepSimulation),
+            SalvaSimulationSet::Writeback => (
+                systems::writeback_particle_kinematics,
+                systems::writeback_particle_densities,
+                pipeline::update_fluid_sensors,
+            )
                 .chain()
                 .in_set(SalvaSimulationSet::Writeback),
         }
     }
 }
 
+impl<PhysicsHooks> SalvaPhysicsPlugin<PhysicsHooks> {
+    pub fn in_schedule(mut self, schedule: impl ScheduleLabel) -> Self {
+        self.schedule = schedule.intern();
+        self
+    }
+
+    pub fn with_custom_world_initialization(mut self, world_setup: SalvaContextInitialization) -> Self {
+        self.world_setup = world_setup;
+        self
+    }
+
+    pub fn with_default_system_setup(mut self, use_default_coupling: bool) -> Self {
+        self.default_system_setup = use_default_coupling;
+        self
+    }
+
+    /// Uses the given Rapier physics hooks type, to match the app's [`RapierPhysicsPlugin`].
+    pub fn with_physics_hooks<NewPhysicsHooks>(self) -> SalvaPhysicsPlugin<NewPhysicsHooks> {
+        SalvaPhysicsPlugin {
+            schedule: self.schedule,
+            default_system_setup: self.default_system_setup,
+            world_setup: self.world_setup,
+            _phantom: PhantomData,
+        }
+    }
+}
+
 impl Default for SalvaPhysicsPlugin {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl Plugin for SalvaPhysicsPlugin {
+impl<PhysicsHooks: SalvaPhysicsHooks> Plugin for SalvaPhysicsPlugin<PhysicsHooks> {
     fn build(&self, app: &mut bevy::prelude::App) {
         app
             .register_type::<DefaultSalvaContext>()
             .register_type::<SalvaContextInitialization>()
-            .register_type::<SalvaContextEntityLink>();
+            .register_type::<SalvaContextEntityLink>()
+            .register_type::<fluid::FluidPositions>()
+            .register_type::<fluid::FluidVelocities>()
+            .register_type::<fluid::FluidAccelerations>()
+            .register_type::<fluid::FluidDensity>()
+            .register_type::<fluid::FluidDensities>()
+            .register_type::<fluid::FluidPressures>()
+            .register_type::<fluid::FluidNonPressureForceConfigs>()
+            .register_type::<fluid::FluidInteractionGroups>()
+            .register_type::<fluid::FluidParticleEdits>()
+            .register_type::<fluid::FluidParticleEditLog>()
+            .register_type::<particle_attributes::FluidParticleColors>()
+            .register_type::<particle_attributes::FluidParticleTemperatures>()
+            .register_type::<pipeline::FluidSensor>()
+            .register_type::<pipeline::FluidOccupancy>()
+            .register_type::<force_field::FluidForceField>()
+            .register_type::<export::FluidExporter>()
+            .register_type::<diagnostics::SalvaStepStats>()
+            .register_type::<diagnostics::SalvaDiagnosticsSettings>()
+            .add_event::<force_field::FluidImpulse>();
+        app.register_type::<material::FluidMaterialHandle>();
+        #[cfg(feature = "rapier")]
+        app
+            .register_type::<rapier_integration::AnalyticBuoyancy>()
+            .register_type::<rapier_integration::CoupleWithRapierContext>()
+            .register_type::<rapier_integration::GravitySource>();
+        #[cfg(feature = "rapier")]
+        app
+            .add_event::<pipeline::FluidContactStarted>()
+            .add_event::<pipeline::FluidContactStopped>();
         
         app.init_resource::<TimestepMode>();
+        app.init_resource::<diagnostics::SalvaDiagnosticsSettings>();
+        diagnostics::register_diagnostics(app);
 
         let default_world_init = app.world().get_resource::<SalvaContextInitialization>();
         if let Some(world_init) = default_world_init {
@@ -167,10 +291,6 @@ impl Plugin for SalvaPhysicsPlugin {
 
             #[cfg(feature = "rapier")]
             {
-                // Ensure that rapier physics is added.
-                if !app.is_plugin_added::<RapierPhysicsPlugin<()>>() {
-                    app.add_plugins(RapierPhysicsPlugin::<()>::default());
-                }
                 app.configure_sets(
                     self.schedule,
                     (
@@ -187,24 +307,81 @@ impl Plugin for SalvaPhysicsPlugin {
             app.add_systems(
                 self.schedule,
                 (
-                    Self::get_systems(SalvaSimulationSet::SyncBackend),
-                    Self::get_systems(SalvaSimulationSet::StepSimulation),
-                    Self::get_systems(SalvaSimulationSet::Writeback),
+                    SalvaPhysicsPlugin::get_systems(SalvaSimulationSet::SyncBackend),
+                    SalvaPhysicsPlugin::get_systems(SalvaSimulationSet::StepSimulation),
+                    SalvaPhysicsPlugin::get_systems(SalvaSimulationSet::Writeback),
+                ),
+            );
+
+            // Color and temperature channels, see `FluidParticleColors` and `FluidParticleTemperatures`.
+            // Colors are mixed inside the salva step by the `ColorMixing` force.
+            app.add_fluid_particle_attribute::<LinearRgba>(self.schedule)
+                .add_diffusible_fluid_particle_attribute::<Real>(self.schedule);
+            app.add_systems(
+                self.schedule,
+                (
+                    color_mixing::sync_fluid_color_mixing
+                        .in_set(SalvaSimulationSet::SyncBackend)
+                        .after(particle_attributes::sync_fluid_particle_attributes::<LinearRgba>),
+                    color_mixing::writeback_fluid_colors
+                        .in_set(SalvaSimulationSet::Writeback)
+                        .after(systems::writeback_particle_kinematics),
                 ),
             );
 
-            // This system needs to run a bit later to ensure that the default RapierContext is created.
-            // The system that initializes the default rapier context isn't public, so this is the workaround
-            // for now.
-            #[cfg(feature = "rapier")]
             app.add_systems(
-                PostStartup,
-                rapier_integration::link_default_contexts.before(SalvaSimulationSet::SyncBackend)
+                self.schedule,
+                (
+                    recording::detach_played_fluids
+                        .in_set(SalvaSimulationSet::SyncBackend)
+                        .before(systems::sync_removals),
+                    recording::record_fluids
+                        .in_set(SalvaSimulationSet::Writeback)
+                        .after(systems::writeback_particle_kinematics),
+                    recording::play_fluid_recordings.in_set(SalvaSimulationSet::Writeback),
+                    export::export_fluid_frames
+                        .in_set(SalvaSimulationSet::Writeback)
+                        .after(systems::writeback_particle_kinematics),
+                    (
+                        diagnostics::register_context_diagnostics,
+                        diagnostics::update_salva_diagnostics,
+                    )
+                        .chain()
+                        .in_set(SalvaSimulationSet::Writeback)
+                        .after(systems::writeback_particle_kinematics),
+                ),
             );
 
             //TODO: implement a TimestepMode like how bevy_rapier has it
         }
     }
+
+    fn finish(&self, app: &mut App) {
+        // Ensure that rapier physics is added. This waits for every plugin to be built, so that a
+        // `RapierPhysicsPlugin` added after this plugin is found.
+        #[cfg(feature = "rapier")]
+        if self.default_system_setup {
+            PhysicsHooks::ensure_rapier_plugin(app);
+        }
+
+        // Materials need the asset plugin, which headless apps may not have. It may be added
+        // after this plugin, so this waits for every plugin to be built.
+        if app.is_plugin_added::<AssetPlugin>() {
+            app.init_asset::<material::FluidMaterial>();
+            #[cfg(feature = "serialize")]
+            app.init_asset_loader::<material::FluidMaterialLoader>();
+
+            if self.default_system_setup {
+                app.add_systems(
+                    self.schedule,
+                    systems::apply_fluid_material_changes
+                        .in_set(SalvaSimulationSet::SyncBackend)
+                        .after(systems::apply_fluid_user_changes)
+                        .before(systems::apply_fluid_particle_edits),
+                );
+            }
+        }
+    }
 }
 
 /// Specifies a default configuration for the default [`SalvaContext`]
@@ -250,10 +427,7 @@ pub fn insert_default_world(
             // Required SalvaConfiguration is added automatically w/ default values
             commands.spawn((
                 Name::new("Salva Context"),
-                SalvaContext {
-                    liquid_world: LiquidWorld::new(solver, *particle_radius, *smoothing_factor),
-                    entity2fluid: HashMap::default(),
-                },            
+                SalvaContext::new(LiquidWorld::new(solver, *particle_radius, *smoothing_factor)),
                 #[cfg(feature = "rapier")]
                 SalvaConfiguration {
                     physics_pipeline_active: None,
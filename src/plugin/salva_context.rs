@@ -1,23 +1,111 @@
-use crate::math::Real;
+use crate::math::{Real, Vect};
 use crate::plugin::{configuration::SalvaConfiguration, SimulationToRenderTime, TimestepMode};
 use bevy::ecs::query::QueryData;
 use bevy::ecs::system::SystemParam;
 use bevy::prelude::{Component, Entity, Mut, Query, Reflect, Time, With};
 use salva::coupling::CouplingManager;
+use salva::kernel::{CubicSplineKernel, Kernel};
 use salva::math::Vector;
-use salva::object::FluidHandle;
+use salva::object::{BoundaryHandle, FluidHandle};
 use salva::LiquidWorld;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ops::{Deref, DerefMut};
+use crate::diagnostics::SalvaStepStats;
+use crate::pipeline::SalvaParticleIndex;
+use crate::fluid::FluidNonPressureForce;
 
 #[derive(Component)]
-#[require(SalvaConfiguration, SimulationToRenderTime)]
+#[require(SalvaConfiguration, SimulationToRenderTime, SalvaStepStats)]
 pub struct SalvaContext {
     pub liquid_world: LiquidWorld,
     pub entity2fluid: HashMap<Entity, FluidHandle>,
+    /// The descriptions of the non-pressure forces of each fluid, in the same order as its
+    /// `nonpressure_forces`. Forces added as trait objects have no description (`None`).
+    pub nonpressure_force_configs: HashMap<FluidHandle, Vec<Option<FluidNonPressureForce>>>,
+    /// The boundaries sampled from Rapier colliders. They are owned by the Rapier coupling, which
+    /// resamples them at every step.
+    pub coupled_boundaries: HashSet<BoundaryHandle>,
+    pub(crate) particle_index: SalvaParticleIndex,
 }
 
 impl SalvaContext {
+    pub fn new(liquid_world: LiquidWorld) -> Self {
+        Self {
+            liquid_world,
+            entity2fluid: HashMap::default(),
+            nonpressure_force_configs: HashMap::default(),
+            coupled_boundaries: HashSet::default(),
+            particle_index: SalvaParticleIndex::default(),
+        }
+    }
+
+    /// The spatial index of the fluid particles of this context, rebuilt after every step.
+    pub fn particle_index(&self) -> &SalvaParticleIndex {
+        &self.particle_index
+    }
+
+    /// The reverse of [`Self::entity2fluid`]: maps each fluid handle to its fluid entity.
+    pub fn fluid2entity(&self) -> HashMap<FluidHandle, Entity> {
+        self.entity2fluid
+            .iter()
+            .map(|(entity, handle)| (*handle, *entity))
+            .collect()
+    }
+
+    /// Estimates the density of every fluid particle of this context by summing the SPH kernel
+    /// over its neighbor fluid particles and boundary samples, found with the
+    /// [particle index](Self::particle_index). The neighbors are at their position at the end of
+    /// the last step.
+    pub fn compute_densities(&self) -> HashMap<FluidHandle, Vec<Real>> {
+        let handles: Vec<FluidHandle> =
+            self.liquid_world.fluids().iter().map(|(handle, _)| handle).collect();
+        self.compute_densities_of(&handles)
+    }
+
+    /// Same as [`Self::compute_densities`], for the given fluids only.
+    pub fn compute_densities_of(&self, handles: &[FluidHandle]) -> HashMap<FluidHandle, Vec<Real>> {
+        let h = self.liquid_world.h();
+        let fluids = self.liquid_world.fluids();
+
+        handles
+            .iter()
+            .filter_map(|handle| Some((*handle, fluids.get(*handle)?)))
+            .map(|(handle, fluid)| {
+                let densities = fluid
+                    .positions
+                    .iter()
+                    .map(|pos| {
+                        let pos = Vect::from(*pos);
+                        let fluid_density: Real = self
+                            .particle_index
+                            .fluid_particles_in_radius(pos, h)
+                            .filter_map(|(neighbor_pos, (neighbor_handle, j))| {
+                                let neighbor = fluids.get(*neighbor_handle)?;
+                                Some(
+                                    neighbor.volumes.get(*j)?
+                                        * neighbor.density0
+                                        * CubicSplineKernel::scalar_apply(neighbor_pos.distance(pos), h),
+                                )
+                            })
+                            .sum();
+                        let boundary_density: Real = self
+                            .particle_index
+                            .boundary_samples_in_radius(pos, h)
+                            .map(|(boundary_pos, volume)| {
+                                volume
+                                    * fluid.density0
+                                    * CubicSplineKernel::scalar_apply(boundary_pos.distance(pos), h)
+                            })
+                            .sum();
+                        fluid_density + boundary_density
+                    })
+                    .collect();
+                (handle, densities)
+            })
+            .collect()
+    }
+
+    /// Steps the simulation with the given coupling, returning the number of salva steps taken.
     pub fn step_with_coupling(
         &mut self,
         time: &Time,
@@ -25,12 +113,14 @@ impl SalvaContext {
         timestep_mode: TimestepMode,
         sim_to_render_time: &mut SimulationToRenderTime,
         coupling: &mut impl CouplingManager,
-    ) {
+    ) -> u32 {
+        let mut steps = 0;
         match timestep_mode {
             TimestepMode::Fixed { dt, substeps } => {
                 let dt = dt / substeps as Real;
                 for _ in 0..substeps {
                     self.liquid_world.step_with_coupling(dt, gravity, coupling);
+                    steps += 1;
                 }
             }
             TimestepMode::Variable {
@@ -41,6 +131,7 @@ impl SalvaContext {
                 let dt = (time.delta_secs() * time_scale).min(max_dt) / substeps as Real;
                 for _ in 0..substeps {
                     self.liquid_world.step_with_coupling(dt, gravity, coupling);
+                    steps += 1;
                 }
             }
             TimestepMode::Interpolated {
@@ -54,26 +145,31 @@ impl SalvaContext {
                     let dt = (dt / substeps as Real) * time_scale;
                     for _ in 0..substeps {
                         self.liquid_world.step_with_coupling(dt, gravity, coupling);
+                        steps += 1;
                     }
 
                     sim_to_render_time.diff -= dt;
                 }
             }
         }
+        steps
     }
 
+    /// Steps the simulation, returning the number of salva steps taken.
     pub fn step_simulation(
         &mut self,
         time: &Time,
         gravity: &Vector<f32>,
         timestep_mode: TimestepMode,
         sim_to_render_time: &mut SimulationToRenderTime,
-    ) {
+    ) -> u32 {
+        let mut steps = 0;
         match timestep_mode {
             TimestepMode::Fixed { dt, substeps } => {
                 let dt = dt / substeps as Real;
                 for _ in 0..substeps {
                     self.liquid_world.step(dt, gravity);
+                    steps += 1;
                 }
             }
             TimestepMode::Variable {
@@ -84,6 +180,7 @@ impl SalvaContext {
                 let dt = (time.delta_secs() * time_scale).min(max_dt) / substeps as Real;
                 for _ in 0..substeps {
                     self.liquid_world.step(dt, gravity);
+                    steps += 1;
                 }
             }
             TimestepMode::Interpolated {
@@ -97,12 +194,14 @@ impl SalvaContext {
                     let dt = (dt / substeps as Real) * time_scale;
                     for _ in 0..substeps {
                         self.liquid_world.step(dt, gravity);
+                        steps += 1;
                     }
 
                     sim_to_render_time.diff -= dt;
                 }
             }
         }
+        steps
     }
 }
 
@@ -110,7 +209,8 @@ impl SalvaContext {
 /// The inner Entity referred to has the component [`SalvaContext`] responsible for handling
 /// its salva data.
 #[derive(Component, Reflect, Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub struct SalvaContextEntityLink(pub Entity);
+#[reflect(Component)]
+pub struct SalvaContextEntityLink(#[entities] pub Entity);
 
 /// ECS query data that queries for entities that contain a salva handle component.
 /// Contains the link the entity has to a salva context.
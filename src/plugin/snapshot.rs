@@ -0,0 +1,333 @@
+use crate::fluid::{FluidInteractionGroups, FluidNonPressureForce};
+use crate::math::{Real, Vect};
+use crate::plugin::SalvaContext;
+use bevy::ecs::entity::{EntityMapper, MapEntities};
+use bevy::prelude::Entity;
+use salva::math::{Point, Vector};
+use salva::object::{Boundary, BoundaryHandle, Fluid, FluidHandle};
+#[cfg(feature = "serialize")]
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// The state of a fluid, as captured by [`SalvaContext::snapshot`].
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct FluidSnapshot {
+    /// The fluid entity, if the fluid was created from one.
+    pub entity: Option<Entity>,
+    pub positions: Vec<Vect>,
+    pub velocities: Vec<Vect>,
+    pub accelerations: Vec<Vect>,
+    pub volumes: Vec<Real>,
+    pub density0: Real,
+    pub interaction_groups: FluidInteractionGroups,
+    /// The descriptions of the fluid's non-pressure forces. Forces added as trait objects have no
+    /// description (`None`).
+    pub nonpressure_forces: Vec<Option<FluidNonPressureForce>>,
+}
+
+/// The state of a boundary, as captured by [`SalvaContext::snapshot`].
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct BoundarySnapshot {
+    pub positions: Vec<Vect>,
+    pub volumes: Vec<Real>,
+    pub interaction_groups: FluidInteractionGroups,
+}
+
+/// The full state of the fluids and boundaries of a [`SalvaContext`].
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct SalvaContextSnapshot {
+    pub fluids: Vec<FluidSnapshot>,
+    pub boundaries: Vec<BoundarySnapshot>,
+}
+
+impl MapEntities for SalvaContextSnapshot {
+    /// Maps the fluid entities of the snapshot, e.g. to load a snapshot saved in another session
+    /// along with a scene.
+    fn map_entities<E: EntityMapper>(&mut self, entity_mapper: &mut E) {
+        for fluid in self.fluids.iter_mut() {
+            if let Some(entity) = fluid.entity.as_mut() {
+                *entity = entity_mapper.get_mapped(*entity);
+            }
+        }
+    }
+}
+
+impl SalvaContext {
+    /// Captures the state of every fluid and boundary of this context.
+    ///
+    /// Boundaries sampled from Rapier colliders are left out: they are resampled by the Rapier
+    /// coupling at every step.
+    pub fn snapshot(&self) -> SalvaContextSnapshot {
+        let fluid2entity = self.fluid2entity();
+
+        let fluids = self
+            .liquid_world
+            .fluids()
+            .iter()
+            .map(|(handle, fluid)| FluidSnapshot {
+                entity: fluid2entity.get(&handle).copied(),
+                positions: fluid.positions.iter().map(|p| Vect::from(*p)).collect(),
+                velocities: fluid.velocities.iter().map(|v| Vect::from(*v)).collect(),
+                accelerations: fluid.accelerations.iter().map(|a| Vect::from(*a)).collect(),
+                volumes: fluid.volumes.clone(),
+                density0: fluid.density0,
+                interaction_groups: fluid.interaction_groups.into(),
+                nonpressure_forces: self
+                    .nonpressure_force_configs
+                    .get(&handle)
+                    .cloned()
+                    .unwrap_or_else(|| vec![None; fluid.nonpressure_forces.len()]),
+            })
+            .collect();
+
+        let boundaries = self
+            .liquid_world
+            .boundaries()
+            .iter()
+            .filter(|(handle, _)| !self.coupled_boundaries.contains(handle))
+            .map(|(_, boundary)| BoundarySnapshot {
+                positions: boundary.positions.iter().map(|p| Vect::from(*p)).collect(),
+                volumes: boundary.volumes.clone(),
+                interaction_groups: boundary.interaction_groups.into(),
+            })
+            .collect();
+
+        SalvaContextSnapshot { fluids, boundaries }
+    }
+
+    /// Replaces the fluids and boundaries of this context by the ones of `snapshot`.
+    ///
+    /// Fluids of the snapshot are recreated, so their handles change: [`Self::entity2fluid`] is
+    /// updated, and the `SalvaFluidHandle` and particle components of fluid entities are updated
+    /// during the next [`SalvaSimulationSet::SyncBackend`](crate::plugin::SalvaSimulationSet).
+    /// Fluids whose entity no longer exists are removed then. When loading a snapshot saved in
+    /// another session, map its entities first (see [`MapEntities`]).
+    ///
+    /// Fluids of entities that aren't in the snapshot (e.g. spawned after it was taken) are kept
+    /// as they are, since their entities still own them.
+    ///
+    /// A fluid whose entity still has a fluid in this context keeps its current non-pressure
+    /// forces. Other fluids get the forces described in the snapshot; forces without a
+    /// description are lost.
+    ///
+    /// Boundaries are matched with the boundaries of this context that aren't sampled from
+    /// Rapier colliders, in order, and only recreated if their number of particles differs.
+    /// Boundaries sampled from Rapier colliders are left to the Rapier coupling.
+    pub fn restore(&mut self, snapshot: &SalvaContextSnapshot) {
+        let fluid2entity = self.fluid2entity();
+        let snapshot_entities: HashSet<Entity> =
+            snapshot.fluids.iter().filter_map(|fluid| fluid.entity).collect();
+        let fluid_handles: Vec<FluidHandle> = self
+            .liquid_world
+            .fluids()
+            .iter()
+            .map(|(handle, _)| handle)
+            .filter(|handle| {
+                fluid2entity
+                    .get(handle)
+                    .is_none_or(|entity| snapshot_entities.contains(entity))
+            })
+            .collect();
+
+        // Keep the live non-pressure forces of fluid entities.
+        let mut live_forces = HashMap::new();
+        for handle in fluid_handles {
+            let Some(fluid) = self.liquid_world.remove_fluid(handle) else {
+                continue;
+            };
+            let force_configs = self.nonpressure_force_configs.remove(&handle).unwrap_or_default();
+            if let Some(entity) = fluid2entity.get(&handle) {
+                self.entity2fluid.remove(entity);
+                live_forces.insert(*entity, (fluid.nonpressure_forces, force_configs));
+            }
+        }
+
+        let particle_radius = self.liquid_world.particle_radius();
+        for fluid_snapshot in snapshot.fluids.iter() {
+            let mut fluid = Fluid::new(
+                fluid_snapshot.positions.iter().map(|p| Point::from(*p)).collect(),
+                particle_radius,
+                fluid_snapshot.density0,
+                fluid_snapshot.interaction_groups.into(),
+            );
+            fluid.velocities = fluid_snapshot.velocities.iter().map(|v| Vector::from(*v)).collect();
+            fluid.accelerations = fluid_snapshot.accelerations.iter().map(|a| Vector::from(*a)).collect();
+            fluid.volumes.clone_from(&fluid_snapshot.volumes);
+
+            let force_configs = match fluid_snapshot.entity.and_then(|e| live_forces.remove(&e)) {
+                Some((forces, force_configs)) => {
+                    fluid.nonpressure_forces = forces;
+                    force_configs
+                }
+                None => {
+                    let force_configs: Vec<_> = fluid_snapshot
+                        .nonpressure_forces
+                        .iter()
+                        .flatten()
+                        .map(|config| Some(*config))
+                        .collect();
+                    fluid.nonpressure_forces = force_configs
+                        .iter()
+                        .flatten()
+                        .map(FluidNonPressureForce::build)
+                        .collect();
+                    force_configs
+                }
+            };
+
+            let handle = self.liquid_world.add_fluid(fluid);
+            self.nonpressure_force_configs.insert(handle, force_configs);
+            if let Some(entity) = fluid_snapshot.entity {
+                self.entity2fluid.insert(entity, handle);
+            }
+        }
+
+        let boundary_handles: Vec<BoundaryHandle> = self
+            .liquid_world
+            .boundaries()
+            .iter()
+            .map(|(handle, _)| handle)
+            .filter(|handle| !self.coupled_boundaries.contains(handle))
+            .collect();
+        for (i, boundary_snapshot) in snapshot.boundaries.iter().enumerate() {
+            let positions: Vec<_> = boundary_snapshot.positions.iter().map(|p| Point::from(*p)).collect();
+            let existing = boundary_handles
+                .get(i)
+                .and_then(|handle| self.liquid_world.boundaries_mut().get_mut(*handle));
+
+            match existing {
+                Some(boundary) if boundary.positions.len() == positions.len() => {
+                    boundary.positions = positions;
+                    boundary.volumes.clone_from(&boundary_snapshot.volumes);
+                    boundary.interaction_groups = boundary_snapshot.interaction_groups.into();
+                }
+                _ => {
+                    if let Some(handle) = boundary_handles.get(i) {
+                        self.liquid_world.remove_boundary(*handle);
+                    }
+                    let mut boundary =
+                        Boundary::new(positions, boundary_snapshot.interaction_groups.into());
+                    boundary.volumes.clone_from(&boundary_snapshot.volumes);
+                    self.liquid_world.add_boundary(boundary);
+                }
+            }
+        }
+        for handle in boundary_handles.iter().skip(snapshot.boundaries.len()) {
+            self.liquid_world.remove_boundary(*handle);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fluid::{FluidPositions, SalvaFluidHandle};
+    use crate::plugin::{systems, DefaultSalvaContext, TimestepMode};
+    use bevy::prelude::*;
+    use salva::solver::DFSPHSolver;
+    use salva::LiquidWorld;
+
+    fn test_app() -> (App, Entity) {
+        let mut app = App::new();
+        app.init_resource::<Time>()
+            .insert_resource(TimestepMode::Fixed { dt: 1.0 / 60.0, substeps: 1 })
+            .add_systems(
+                Update,
+                (
+                    systems::sync_removals,
+                    systems::sync_restored_fluids,
+                    systems::init_fluids,
+                    systems::apply_fluid_user_changes,
+                    systems::step_simulation,
+                    systems::writeback_particle_kinematics,
+                )
+                    .chain(),
+            );
+        let solver: DFSPHSolver = DFSPHSolver::new();
+        let context = app
+            .world_mut()
+            .spawn((
+                SalvaContext::new(LiquidWorld::new(solver, 0.05, 2.0)),
+                DefaultSalvaContext,
+            ))
+            .id();
+        (app, context)
+    }
+
+    fn particle_block(offset: Vect) -> FluidPositions {
+        FluidPositions((0..4).map(|i| offset + Vect::X * i as Real * 0.1).collect())
+    }
+
+    fn fluid_count(context: &SalvaContext) -> usize {
+        context.liquid_world.fluids().iter().count()
+    }
+
+    #[test]
+    fn restore_keeps_fluids_spawned_after_snapshot() {
+        let (mut app, context) = test_app();
+        let before = app.world_mut().spawn(particle_block(Vect::ZERO)).id();
+        app.update();
+        let snapshot = app.world().get::<SalvaContext>(context).unwrap().snapshot();
+
+        let after = app.world_mut().spawn(particle_block(Vect::Y)).id();
+        app.update();
+        app.world_mut().get_mut::<SalvaContext>(context).unwrap().restore(&snapshot);
+        // Steps with the restored context must not panic.
+        app.update();
+        app.update();
+
+        let salva_context = app.world().get::<SalvaContext>(context).unwrap();
+        assert_eq!(fluid_count(salva_context), 2);
+        for entity in [before, after] {
+            let handle = app.world().get::<SalvaFluidHandle>(entity).unwrap().0;
+            assert_eq!(salva_context.entity2fluid.get(&entity), Some(&handle));
+            assert!(salva_context.liquid_world.fluids().get(handle).is_some());
+        }
+    }
+
+    #[test]
+    fn restore_removes_fluids_of_missing_entities() {
+        let (mut app, context) = test_app();
+        let fluid = app.world_mut().spawn(particle_block(Vect::ZERO)).id();
+        app.update();
+        let snapshot = app.world().get::<SalvaContext>(context).unwrap().snapshot();
+
+        app.world_mut().despawn(fluid);
+        app.update();
+        app.world_mut().get_mut::<SalvaContext>(context).unwrap().restore(&snapshot);
+        app.update();
+
+        let salva_context = app.world().get::<SalvaContext>(context).unwrap();
+        assert_eq!(fluid_count(salva_context), 0);
+        assert!(salva_context.entity2fluid.is_empty());
+    }
+
+    #[test]
+    fn mapped_snapshot_restores_onto_new_entities() {
+        let (mut app, context) = test_app();
+        let fluid = app.world_mut().spawn(particle_block(Vect::ZERO)).id();
+        app.update();
+        let mut snapshot = app.world().get::<SalvaContext>(context).unwrap().snapshot();
+
+        // The fluid entity as loaded in another session.
+        let loaded = app.world_mut().spawn(particle_block(Vect::Y)).id();
+        app.world_mut().despawn(fluid);
+        app.update();
+        let mut entity_map = bevy::ecs::entity::EntityHashMap::default();
+        entity_map.insert(fluid, loaded);
+        snapshot.map_entities(&mut entity_map);
+        app.world_mut().get_mut::<SalvaContext>(context).unwrap().restore(&snapshot);
+        app.update();
+
+        let salva_context = app.world().get::<SalvaContext>(context).unwrap();
+        assert_eq!(fluid_count(salva_context), 1);
+        let handle = app.world().get::<SalvaFluidHandle>(loaded).unwrap().0;
+        assert_eq!(salva_context.entity2fluid.get(&loaded), Some(&handle));
+        // The particles are the ones of the snapshot, not the ones the entity was spawned with.
+        let positions = app.world().get::<FluidPositions>(loaded).unwrap();
+        assert!(positions.0[0].y < 0.5);
+    }
+}
@@ -1,14 +1,22 @@
-use crate::fluid::{FluidAccelerations, FluidDensity, FluidInteractionGroups, FluidNonPressureForces, FluidPositions, FluidVelocities, SalvaFluidHandle};
-use bevy::prelude::{error, Changed, Commands, Entity, Query, RemovedComponents, Res, Time, With, Without};
+use crate::fluid::{FluidAccelerations, FluidDensity, FluidInteractionGroups, FluidNonPressureForceConfigs, FluidNonPressureForces, FluidPositions, FluidVelocities, SalvaFluidHandle};
+use bevy::prelude::{error, warn, AssetEvent, AssetId, AssetServer, Assets, Changed, Commands, Entity, EventReader, Local, Or, Query, Ref, RemovedComponents, Res, Time, With, Without};
+use std::collections::{HashMap, HashSet};
+#[cfg(feature = "rapier")]
 use bevy_rapier::prelude::RapierConfiguration;
 use salva::object::interaction_groups::InteractionGroups;
-use salva::{math::Point, object::Fluid};
+use salva::{math::Point, object::{Fluid, FluidHandle}};
 use salva::math::Vector;
-use crate::fluid::{AppendNonPressureForces, RemoveNonPressureForcesAt};
-use crate::math::Vect;
+use bevy::platform::time::Instant;
+use crate::diagnostics::SalvaStepStats;
+use crate::fluid::{AppendNonPressureForces, FluidDensities, FluidNonPressureForce, FluidParticleEdit, FluidParticleEditLog, FluidParticleEdits, FluidPressures, RemoveNonPressureForcesAt};
+use crate::math::{Real, Vect};
 use crate::plugin::salva_context::SalvaContext;
 use crate::plugin::{DefaultSalvaContext, SalvaConfiguration, SalvaContextAccess, SalvaContextEntityLink, SimulationToRenderTime, TimestepMode, WriteSalvaContext};
+#[cfg(feature = "rapier")]
 use crate::rapier_integration::SalvaRapierCoupling;
+use crate::material::{AppliedFluidMaterial, FluidMaterial, FluidMaterialHandle};
+use crate::recording::FluidPlayer;
+use crate::render::FluidDebugColor;
 use crate::utils;
 
 pub fn init_fluids(
@@ -20,12 +28,17 @@ pub fn init_fluids(
             &FluidPositions,
             Option<&FluidDensity>,
             Option<&mut FluidNonPressureForces>,
+            Option<&FluidNonPressureForceConfigs>,
             Option<&FluidInteractionGroups>,
+            Option<&FluidMaterialHandle>,
         ),
-        Without<SalvaFluidHandle>,
+        (Without<SalvaFluidHandle>, Without<FluidPlayer>),
     >,
     q_default_context: Query<Entity, With<DefaultSalvaContext>>,
     mut q_contexts: Query<&mut SalvaContext>,
+    materials: Option<Res<Assets<FluidMaterial>>>,
+    asset_server: Option<Res<AssetServer>>,
+    mut unresolved_materials: Local<HashSet<Entity>>,
 ) {
     for (
         entity,
@@ -33,25 +46,69 @@ pub fn init_fluids(
         particle_positions,
         density,
         nonpressure_forces,
+        nonpressure_force_configs,
         fluid_interaction_groups,
+        material_handle,
     ) in new_fluids.iter_mut() {
+        // Wait for the material of the fluid to be loaded.
+        let material = match material_handle {
+            Some(handle) => {
+                match materials.as_ref().and_then(|materials| materials.get(&handle.0)) {
+                    Some(material) => {
+                        unresolved_materials.remove(&entity);
+                        Some(material)
+                    }
+                    None => {
+                        let unresolvable = materials.is_none()
+                            || asset_server
+                                .as_ref()
+                                .is_some_and(|server| server.load_state(&handle.0).is_failed());
+                        if unresolvable && unresolved_materials.insert(entity) {
+                            warn!(
+                                "The FluidMaterial of fluid entity {entity} can't be resolved \
+                                (was the AssetPlugin added?). The fluid won't be created until it is."
+                            );
+                        }
+                        continue;
+                    }
+                }
+            }
+            None => None,
+        };
+
         let mut entity_cmd = commands.entity(entity);
 
-        let density = density.map_or_else(|| 1000.0, |d| d.density0);
+        let density = density.map_or_else(
+            || material.map_or(1000.0, |material| material.density0),
+            |d| d.density0
+        );
+        let fluid_interaction_groups = fluid_interaction_groups
+            .or(material.map(|material| &material.interaction_groups));
+        if let Some(material) = material {
+            if let Some(color) = material.debug_color {
+                entity_cmd.insert_if_new(FluidDebugColor(color));
+            }
+            entity_cmd.insert(AppliedFluidMaterial {
+                nonpressure_force_count: material.nonpressure_forces.len(),
+                debug_color: material.debug_color,
+            });
+        }
 
         let particle_positions: Vec<_> = particle_positions
             .iter()
             .map(|v| Point::from(*v))
             .collect();
 
-        let context_entity = context_link.map_or_else(
-            || {
+        // Fluids linked to a context that doesn't exist (e.g. loaded from a scene that doesn't
+        // contain it) fall back to the default context.
+        let context_entity = context_link
+            .map(|link| link.0)
+            .filter(|context_entity| q_contexts.contains(*context_entity))
+            .or_else(|| {
                 let context_entity = q_default_context.get_single().ok()?;
                 entity_cmd.insert(SalvaContextEntityLink(context_entity));
                 Some(context_entity)
-            },
-            |link| Some(link.0)
-        );
+            });
 
         let Some(context_entity) = context_entity else {
             continue;
@@ -71,7 +128,21 @@ pub fn init_fluids(
                 |groups| (*groups).into()
             )
         );
+        let mut force_configs = Vec::new();
+        if let Some(material) = material {
+            for config in material.nonpressure_forces.iter() {
+                salva_fluid.nonpressure_forces.push(config.build());
+                force_configs.push(Some(*config));
+            }
+        }
+        if let Some(nonpressure_force_configs) = nonpressure_force_configs {
+            for config in nonpressure_force_configs.0.iter() {
+                salva_fluid.nonpressure_forces.push(config.build());
+                force_configs.push(Some(*config));
+            }
+        }
         if let Some(mut nonpressure_forces) = nonpressure_forces {
+            force_configs.extend(nonpressure_forces.0.iter().map(|_| None));
             salva_fluid
                 .nonpressure_forces
                 .append(&mut nonpressure_forces.0);
@@ -79,6 +150,7 @@ pub fn init_fluids(
         let fluid_handle = context.liquid_world.add_fluid(salva_fluid);
         entity_cmd.insert(SalvaFluidHandle(fluid_handle));
         context.entity2fluid.insert(entity, fluid_handle);
+        context.nonpressure_force_configs.insert(fluid_handle, force_configs);
     }
 }
 
@@ -108,6 +180,11 @@ pub fn apply_fluid_user_changes(
     // Handles nonpressure forces the user wants to append to fluids
     for (handle, link, mut appends) in append_q.iter_mut() {
         let mut context = context_writer.context(link);
+        context
+            .nonpressure_force_configs
+            .entry(handle.0)
+            .or_default()
+            .extend(appends.0.iter().map(|_| None));
         context
             .liquid_world
             .fluids_mut()
@@ -120,6 +197,11 @@ pub fn apply_fluid_user_changes(
     // Handles nonpressure forces the user wants to remove from fluids
     for (handle, link, mut removals) in remove_at_q.iter_mut() {
         let mut context = context_writer.context(link);
+        if let Some(force_configs) = context.nonpressure_force_configs.get_mut(&handle.0) {
+            for i in removals.0.iter() {
+                if *i < force_configs.len() { force_configs.remove(*i); }
+            }
+        }
         let nonpressure_forces = &mut context
             .liquid_world
             .fluids_mut()
@@ -204,6 +286,227 @@ pub fn apply_fluid_user_changes(
     }
 }
 
+/// Applies [`FluidMaterial`]s again to their running fluids when they are modified, or when the
+/// [`FluidMaterialHandle`] of a fluid is replaced. Particles are left untouched.
+///
+/// Fluids whose material wasn't loaded yet when they were created get it applied, and their
+/// [`AppliedFluidMaterial`] inserted, as soon as it is loaded.
+pub fn apply_fluid_material_changes(
+    mut commands: Commands,
+    mut context_writer: WriteSalvaContext,
+    mut material_events: EventReader<AssetEvent<FluidMaterial>>,
+    materials: Res<Assets<FluidMaterial>>,
+    mut fluids: Query<(
+        Entity,
+        &SalvaFluidHandle,
+        &SalvaContextEntityLink,
+        Ref<FluidMaterialHandle>,
+        Option<&mut AppliedFluidMaterial>,
+        Option<&FluidDensity>,
+        Option<&FluidInteractionGroups>,
+        Option<&mut FluidDebugColor>,
+    )>,
+) {
+    let modified: HashSet<AssetId<FluidMaterial>> = material_events
+        .read()
+        .filter_map(|event| match event {
+            AssetEvent::Modified { id } => Some(*id),
+            _ => None,
+        })
+        .collect();
+
+    for (
+        entity,
+        handle,
+        link,
+        material_handle,
+        applied,
+        density,
+        groups,
+        debug_color,
+    ) in fluids.iter_mut() {
+        if applied.is_some()
+            && !material_handle.is_changed()
+            && !modified.contains(&material_handle.0.id())
+        {
+            continue;
+        }
+        let Some(material) = materials.get(&material_handle.0) else {
+            continue;
+        };
+
+        let mut context = context_writer.context(link);
+        let previous = applied.as_deref().cloned().unwrap_or_default();
+        let force_count = previous.nonpressure_force_count.min(
+            context.liquid_world.fluids().get(handle.0).unwrap().nonpressure_forces.len()
+        );
+        let force_configs = context.nonpressure_force_configs.entry(handle.0).or_default();
+        let config_count = force_count.min(force_configs.len());
+        force_configs.splice(
+            0..config_count,
+            material.nonpressure_forces.iter().map(|config| Some(*config)),
+        );
+
+        let fluid = context.liquid_world
+            .fluids_mut()
+            .get_mut(handle.0)
+            .unwrap();
+        fluid.nonpressure_forces.splice(
+            0..force_count,
+            material.nonpressure_forces.iter().map(FluidNonPressureForce::build),
+        );
+        if density.is_none() {
+            fluid.density0 = material.density0;
+        }
+        if groups.is_none() {
+            fluid.interaction_groups = material.interaction_groups.into();
+        }
+
+        // Only replace the debug color if it still is the one the material gave.
+        match (debug_color, material.debug_color) {
+            (Some(mut debug_color), Some(color)) => {
+                if previous.debug_color == Some(debug_color.0) {
+                    debug_color.0 = color;
+                }
+            }
+            (None, Some(color)) if applied.is_none() => {
+                commands.entity(entity).insert_if_new(FluidDebugColor(color));
+            }
+            _ => {}
+        }
+
+        let new_applied = AppliedFluidMaterial {
+            nonpressure_force_count: material.nonpressure_forces.len(),
+            debug_color: material.debug_color,
+        };
+        match applied {
+            Some(mut applied) => *applied = new_applied,
+            None => {
+                commands.entity(entity).insert(new_applied);
+            }
+        }
+    }
+}
+
+/// Applies the queued [`FluidParticleEdits`] to the fluid and its particle components.
+pub fn apply_fluid_particle_edits(
+    mut context_writer: WriteSalvaContext,
+    mut fluids: Query<
+        (
+            &SalvaFluidHandle,
+            &SalvaContextEntityLink,
+            &mut FluidParticleEdits,
+            &mut FluidParticleEditLog,
+            &mut FluidPositions,
+            &mut FluidVelocities,
+            &mut FluidAccelerations,
+        ),
+        Changed<FluidParticleEdits>,
+    >,
+) {
+    for (handle, link, mut edits, mut log, mut positions, mut vels, mut accs) in fluids.iter_mut() {
+        if edits.0.is_empty() {
+            continue;
+        }
+        let mut context = context_writer.context(link);
+        let volume = utils::particle_volume(context.liquid_world.particle_radius());
+        let fluid = context.liquid_world
+            .fluids_mut()
+            .get_mut(handle.0)
+            .unwrap();
+
+        let edits = std::mem::take(&mut edits.0);
+        for edit in edits.iter() {
+            let (new_positions, new_velocities) = match edit {
+                FluidParticleEdit::Add { positions, velocities } => {
+                    (positions.as_slice(), velocities.as_deref())
+                }
+                _ => (&[][..], None),
+            };
+            let new_velocity = |k: usize| new_velocities
+                .and_then(|v| v.get(k).copied())
+                .unwrap_or(Vect::ZERO);
+
+            edit.apply_to(&mut fluid.positions, |k| Point::from(new_positions[k]));
+            edit.apply_to(&mut fluid.velocities, |k| Vector::from(new_velocity(k)));
+            edit.apply_to(&mut fluid.accelerations, |_| Vector::zeros());
+            edit.apply_to(&mut fluid.volumes, |_| volume);
+
+            // Bypass change detection so that `apply_fluid_user_changes` doesn't reset the fluid.
+            edit.apply_to(&mut positions.bypass_change_detection().0, |k| new_positions[k]);
+            edit.apply_to(&mut vels.bypass_change_detection().0, new_velocity);
+            edit.apply_to(&mut accs.bypass_change_detection().0, |_| Vect::ZERO);
+        }
+
+        log.generation += 1;
+        log.edits = edits;
+    }
+}
+
+/// Updates the fluid handles and particle components of fluid entities whose fluid was recreated
+/// by [`SalvaContext::restore`], and removes the restored fluids whose entity is gone.
+pub fn sync_restored_fluids(
+    mut context_writer: WriteSalvaContext,
+    mut fluids: Query<(
+        Entity,
+        &SalvaContextEntityLink,
+        &mut SalvaFluidHandle,
+        &mut FluidPositions,
+        &mut FluidVelocities,
+        &mut FluidAccelerations,
+    )>,
+) {
+    for (context_entity, mut context) in context_writer.salva_context.iter_mut() {
+        let orphans: Vec<(Entity, FluidHandle)> = context
+            .entity2fluid
+            .iter()
+            .filter(|(entity, _)| {
+                !fluids
+                    .get(**entity)
+                    .is_ok_and(|(_, link, ..)| link.0 == context_entity)
+            })
+            .map(|(entity, handle)| (*entity, *handle))
+            .collect();
+        if orphans.is_empty() {
+            continue;
+        }
+        // The entities of these fluids were despawned, or never existed in this session.
+        for (entity, handle) in orphans {
+            context.entity2fluid.remove(&entity);
+            context.nonpressure_force_configs.remove(&handle);
+            context.liquid_world.remove_fluid(handle);
+        }
+    }
+
+    for (entity, link, mut handle, mut positions, mut vels, mut accs) in fluids.iter_mut() {
+        let Ok(context) = context_writer.salva_context.get(link.0) else {
+            continue;
+        };
+        let Some(restored_handle) = context.entity2fluid.get(&entity) else {
+            continue;
+        };
+        if *restored_handle == handle.0 {
+            continue;
+        }
+
+        handle.0 = *restored_handle;
+        let fluid = context.liquid_world.fluids().get(handle.0).unwrap();
+        // Bypass change detection so that `apply_fluid_user_changes` doesn't reset the fluid.
+        positions.bypass_change_detection().0 = fluid.positions
+            .iter()
+            .map(|v| Vect::from(*v))
+            .collect();
+        vels.bypass_change_detection().0 = fluid.velocities
+            .iter()
+            .map(|v| Vect::from(*v))
+            .collect();
+        accs.bypass_change_detection().0 = fluid.accelerations
+            .iter()
+            .map(|v| Vect::from(*v))
+            .collect();
+    }
+}
+
 pub fn sync_removals(
     mut removed_particle_positions: RemovedComponents<FluidPositions>,
     mut removed_fluids: RemovedComponents<SalvaFluidHandle>,
@@ -221,6 +524,7 @@ pub fn sync_removals(
             })
         {
             context.liquid_world.remove_fluid(handle);
+            context.nonpressure_force_configs.remove(&handle);
         }
     }
 }
@@ -228,24 +532,64 @@ pub fn sync_removals(
 /// The system that steps [`SalvaContext`]s that run independently.
 /// See `SalvaConfiguration.physics_pipeline_active` for more details.
 pub fn step_simulation(
-    mut salva_context: Query<(&mut SalvaContext, &SalvaConfiguration, &mut SimulationToRenderTime)>,
+    mut salva_context: Query<(
+        &mut SalvaContext,
+        &SalvaConfiguration,
+        &mut SimulationToRenderTime,
+        &mut SalvaStepStats,
+    )>,
     timestep_mode: Res<TimestepMode>,
     time: Res<Time>,
 ) {
-    for (mut context, config, mut sim_to_render_time) in salva_context.iter_mut() {
+    for (mut context, config, mut sim_to_render_time, mut stats) in salva_context.iter_mut() {
         // If this context runs independently and its physics pipeline is active,
         // step its simulation.
         if config.physics_pipeline_active.is_some_and(|active| active) {
-            context.step_simulation(
+            let start = Instant::now();
+            let simulated_time = timestep_mode.step_delta(&time, &sim_to_render_time);
+            let solver_steps = context.step_simulation(
                 &time,
                 &config.gravity.into(),
                 timestep_mode.clone(),
                 &mut sim_to_render_time
             );
+            *stats = SalvaStepStats {
+                step_time: start.elapsed(),
+                solver_steps,
+                simulated_time,
+                coupled: false,
+            };
+        } else {
+            // Coupled contexts are stepped by `step_simulation_rapier_coupling`, which overwrites
+            // these stats if it steps them.
+            *stats = SalvaStepStats::default();
         }
     }
 }
 
+/// Whether the simulation of a [`SalvaContext`] is stepped, either independently or through its
+/// coupling with Rapier.
+pub fn is_simulation_active(
+    config: &SalvaConfiguration,
+    #[cfg(feature = "rapier")]
+    rapier_coupling: Option<&SalvaRapierCoupling>,
+    #[cfg(feature = "rapier")]
+    rapier_configs: &Query<&RapierConfiguration>,
+) -> bool {
+    // Check if salva ctx is not independent is coupled to Rapier, and Rapier physics is active
+    #[cfg(feature = "rapier")]
+    let coupled_and_active = config.physics_pipeline_active.is_none() &&
+        rapier_coupling.is_some_and(|coupling| {
+            rapier_configs
+                .get(coupling.rapier_context_entity)
+                .is_ok_and(|config| config.physics_pipeline_active)
+        });
+    #[cfg(not(feature = "rapier"))]
+    let coupled_and_active = false;
+
+    config.physics_is_independently_active() || coupled_and_active
+}
+
 /// Write back fluid particle positions, velocities, and accelerations.
 pub fn writeback_particle_kinematics(
     read_context: SalvaContextAccess,
@@ -270,24 +614,21 @@ pub fn writeback_particle_kinematics(
         mut accs,
     ) in fluid_pos_q.iter_mut() {
         let config = salva_configs.get(link.0).unwrap();
-
         #[cfg(not(feature = "rapier"))]
-        let should_writeback = config.physics_is_independently_active();
-
-        // Check if salva ctx is not independent is coupled to Rapier, and Rapier physics is active
-        #[cfg(feature = "rapier")]
-        let rapier_coupling = rapier_couplings.get(link.0);
+        let should_writeback = is_simulation_active(config);
         #[cfg(feature = "rapier")]
-        let should_writeback = config.physics_pipeline_active.is_none() &&
-            rapier_coupling.is_ok() &&
-            rapier_configs
-                .get(rapier_coupling.unwrap().rapier_context_entity)
-                .unwrap()
-                .physics_pipeline_active;
+        let should_writeback = is_simulation_active(
+            config,
+            rapier_couplings.get(link.0).ok(),
+            &rapier_configs,
+        );
 
         if should_writeback {
             let context = read_context.context(link);
-            let fluid = &context.liquid_world.fluids().get(handle.0).unwrap();
+            // The fluid may have been removed by a restore since the last sync.
+            let Some(fluid) = context.liquid_world.fluids().get(handle.0) else {
+                continue;
+            };
             **positions = fluid.positions
                 .iter()
                 .map(|v| Vect::from(*v))
@@ -304,3 +645,75 @@ pub fn writeback_particle_kinematics(
     }
 }
 
+
+/// Write back fluid particle densities and pressures, for fluids with [`FluidDensities`] or
+/// [`FluidPressures`].
+pub fn writeback_particle_densities(
+    read_context: SalvaContextAccess,
+    salva_configs: Query<&SalvaConfiguration>,
+    #[cfg(feature = "rapier")]
+    rapier_configs: Query<&RapierConfiguration>,
+    #[cfg(feature = "rapier")]
+    rapier_couplings: Query<&SalvaRapierCoupling>,
+    mut fluid_q: Query<
+        (
+            &SalvaFluidHandle,
+            &SalvaContextEntityLink,
+            Option<&mut FluidDensities>,
+            Option<&mut FluidPressures>,
+        ),
+        Or<(With<FluidDensities>, With<FluidPressures>)>,
+    >,
+) {
+    // Only the fluids with densities or pressures are evaluated, once per context.
+    let mut requested: HashMap<Entity, Vec<FluidHandle>> = HashMap::new();
+    for (handle, link, _, _) in fluid_q.iter() {
+        let config = salva_configs.get(link.0).unwrap();
+        #[cfg(not(feature = "rapier"))]
+        let should_writeback = is_simulation_active(config);
+        #[cfg(feature = "rapier")]
+        let should_writeback = is_simulation_active(
+            config,
+            rapier_couplings.get(link.0).ok(),
+            &rapier_configs,
+        );
+
+        if should_writeback {
+            requested.entry(link.0).or_default().push(handle.0);
+        }
+    }
+
+    let mut context_densities: HashMap<Entity, HashMap<FluidHandle, Vec<Real>>> = requested
+        .into_iter()
+        .filter_map(|(context_entity, handles)| {
+            let context = read_context.salva_context.get(context_entity).ok()?;
+            Some((context_entity, context.compute_densities_of(&handles)))
+        })
+        .collect();
+
+    for (handle, link, densities, pressures) in fluid_q.iter_mut() {
+        let Some(fluid_densities) = context_densities
+            .get_mut(&link.0)
+            .and_then(|densities| densities.remove(&handle.0))
+        else {
+            continue;
+        };
+
+        if let Some(mut pressures) = pressures {
+            let Some(fluid) = read_context
+                .try_context(link)
+                .and_then(|context| context.liquid_world.fluids().get(handle.0))
+            else {
+                continue;
+            };
+            let stiffness = pressures.stiffness;
+            **pressures = fluid_densities
+                .iter()
+                .map(|density| FluidPressures::estimate(*density, fluid.density0, stiffness))
+                .collect();
+        }
+        if let Some(mut densities) = densities {
+            densities.0 = fluid_densities;
+        }
+    }
+}
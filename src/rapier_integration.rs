@@ -4,12 +4,19 @@ use crate::plugin::{
     DefaultSalvaContext, SalvaConfiguration, SalvaContext, SalvaContextEntityLink,
     SalvaContextInitialization, SimulationToRenderTime, TimestepMode, WriteSalvaContext,
 };
-use bevy::prelude::{Commands, Component, Entity, Query, Res, Time, With, Without};
+use crate::diagnostics::SalvaStepStats;
+use crate::math::{Real, Vect};
+use bevy::platform::time::Instant;
+use bevy::prelude::{
+    Commands, Component, Entity, Has, Name, Query, Reflect, Res, Time, With, Without,
+};
 use bevy_rapier::geometry::RapierColliderHandle;
 use bevy_rapier::parry::math::Point;
+use bevy_rapier::parry::query::PointQuery;
 use bevy_rapier::plugin::{DefaultRapierContext, RapierConfiguration, WriteRapierContext};
 use bevy_rapier::prelude::{CollisionGroups, RapierContextEntityLink};
 use salva::integrations::rapier::{ColliderCouplingSet, ColliderSampling};
+use salva::kernel::{CubicSplineKernel, Kernel};
 use salva::object::interaction_groups::InteractionGroups;
 use salva::object::{Boundary, BoundaryHandle};
 
@@ -82,6 +89,77 @@ pub struct SalvaRapierCoupling {
     pub coupling: ColliderCouplingSet,
 }
 
+/// Add this to a [`SalvaContext`] entity to couple its simulation with a [`RapierContext`]
+/// entity.
+///
+/// The target is resolved every frame. If the coupled Rapier context is despawned, the
+/// [`SalvaRapierCoupling`] is removed and rebuilt as soon as the target resolves to a Rapier
+/// context again, e.g. once a new [`DefaultRapierContext`] is spawned. The Rapier gravity is
+/// copied into the [`SalvaConfiguration`] when coupling.
+///
+/// With [`SalvaContextInitialization::InitializeDefaultSalvaContext`], the
+/// [`DefaultSalvaContext`] is given [`CoupleWithRapierContext::Default`] unless it already has
+/// this component or a coupling.
+#[derive(Component, Reflect, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CoupleWithRapierContext {
+    /// Couple with the Rapier context entity with a [`DefaultRapierContext`] component.
+    Default,
+    /// Couple with this Rapier context entity.
+    ///
+    /// The coupling is not rebuilt once this entity is despawned, until the component is pointed
+    /// to another one.
+    Entity(Entity),
+    /// Couple with the Rapier context entity with this [`Name`].
+    Named(String),
+}
+
+/// Add this to a coupled [`SalvaContext`] entity to choose where its gravity comes from.
+///
+/// Contexts without this component behave as [`GravitySource::Own`].
+#[derive(Component, Reflect, Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum GravitySource {
+    /// Use [`SalvaConfiguration::gravity`].
+    #[default]
+    Own,
+    /// Use the gravity of the coupled [`RapierConfiguration`], read at every tick by
+    /// [`sync_rapier_gravity`].
+    ///
+    /// [`SalvaConfiguration::gravity`] is overwritten with it so that it stays accurate.
+    FollowRapier,
+}
+
+/// Add this to an entity with a [`Collider`](bevy_rapier::prelude::Collider) attached to a
+/// rigid body to apply buoyancy and drag computed analytically from the surrounding fluid.
+///
+/// This complements [`RapierColliderSampling`]: with a large particle radius, too few particles
+/// touch small bodies for the boundary coupling alone to keep them afloat. The collider is
+/// sampled with a regular lattice of points, and the fluid fraction, rest density and velocity
+/// at each point are estimated from the neighboring particles with the SPH kernel.
+#[derive(Component, Reflect, Copy, Clone, Debug, PartialEq)]
+pub struct AnalyticBuoyancy {
+    /// Multiplier applied to the Archimedes force (1.0 is physically accurate).
+    pub buoyancy_scale: Real,
+    /// Linear drag coefficient, per unit of submerged volume.
+    pub linear_drag: Real,
+    /// Quadratic drag coefficient, per unit of submerged volume.
+    pub quadratic_drag: Real,
+    /// The distance between two sample points inside the collider.
+    ///
+    /// If `None`, twice the particle radius of the [`SalvaContext`] is used.
+    pub sample_spacing: Option<Real>,
+}
+
+impl Default for AnalyticBuoyancy {
+    fn default() -> Self {
+        Self {
+            buoyancy_scale: 1.0,
+            linear_drag: 0.0,
+            quadratic_drag: 0.0,
+            sample_spacing: None,
+        }
+    }
+}
+
 // WIP: for now, just assume that everything is run in bevy's fixed update step
 pub fn step_simulation_rapier_coupling(
     mut salva_context_q: Query<(
@@ -89,27 +167,36 @@ pub fn step_simulation_rapier_coupling(
         &mut SalvaRapierCoupling,
         &SalvaConfiguration,
         &mut SimulationToRenderTime,
+        &mut SalvaStepStats,
     )>,
     timestep_mode: Res<TimestepMode>,
     mut write_rapier_context: WriteRapierContext<()>,
     rapier_configs: Query<&RapierConfiguration>,
     time: Res<Time>,
 ) {
-    for (mut context, mut link, config, mut sim_to_render_time) in salva_context_q.iter_mut() {
-        // Skip if this SalvaContext runs independently
+    for (mut context, mut link, config, mut sim_to_render_time, mut stats) in
+        salva_context_q.iter_mut()
+    {
+        // Skip if this SalvaContext runs independently, its stats were recorded by
+        // `step_simulation`.
         if config.physics_pipeline_active.is_some() {
             continue;
         }
-        let (_, mut colliders, _, _, mut rigidbody_set) = write_rapier_context
-            .rapier_context
-            .get_mut(link.rapier_context_entity)
-            .expect("Couldn't find RapierContext coupled to SalvaContext entity {entity}");
-
-        let rapier_config = rapier_configs
-            .get(link.rapier_context_entity)
-            .expect("RapierContext entity doesn't have a RapierConfiguration!");
+        // The coupled RapierContext was despawned, the coupling is rebuilt by
+        // `couple_rapier_contexts` once its target resolves again.
+        let (Ok((_, mut colliders, _, _, mut rigidbody_set)), Ok(rapier_config)) = (
+            write_rapier_context
+                .rapier_context
+                .get_mut(link.rapier_context_entity),
+            rapier_configs.get(link.rapier_context_entity),
+        ) else {
+            *stats = SalvaStepStats::default();
+            continue;
+        };
         if rapier_config.physics_pipeline_active {
-            context.step_with_coupling(
+            let start = Instant::now();
+            let simulated_time = timestep_mode.step_delta(&time, &sim_to_render_time);
+            let solver_steps = context.step_with_coupling(
                 &time,
                 &config.gravity.into(),
                 timestep_mode.clone(),
@@ -118,10 +205,236 @@ pub fn step_simulation_rapier_coupling(
                     .coupling
                     .as_manager_mut(&mut colliders.colliders, &mut rigidbody_set.bodies),
             );
+            *stats = SalvaStepStats {
+                step_time: start.elapsed(),
+                solver_steps,
+                simulated_time,
+                coupled: true,
+            };
+        } else {
+            *stats = SalvaStepStats::default();
+        }
+    }
+}
+
+/// The system that copies the gravity of the coupled [`RapierConfiguration`] to the
+/// [`SalvaConfiguration`] of the [`SalvaContext`] entities with [`GravitySource::FollowRapier`].
+///
+/// It runs in the sync backend set, so that the force fields and the step of this tick already
+/// use the gravity of the Rapier context.
+pub fn sync_rapier_gravity(
+    mut salva_contexts: Query<(&mut SalvaConfiguration, &SalvaRapierCoupling, &GravitySource)>,
+    rapier_configs: Query<&RapierConfiguration>,
+) {
+    for (mut config, link, gravity_source) in salva_contexts.iter_mut() {
+        if *gravity_source != GravitySource::FollowRapier {
+            continue;
+        }
+        let Ok(rapier_config) = rapier_configs.get(link.rapier_context_entity) else {
+            continue;
+        };
+        if config.gravity != rapier_config.gravity {
+            config.gravity = rapier_config.gravity;
+        }
+    }
+}
+
+/// The system that builds the [`SalvaRapierCoupling`] of [`SalvaContext`] entities with a
+/// [`CoupleWithRapierContext`] component.
+///
+/// When the target of the coupling changes or its Rapier context is despawned, the boundaries
+/// sampled from the previously coupled Rapier context are removed so that
+/// [`sample_rapier_colliders`] samples the colliders of the new one.
+pub fn couple_rapier_contexts(
+    mut commands: Commands,
+    mut salva_contexts: Query<(
+        Entity,
+        &mut SalvaContext,
+        &mut SalvaConfiguration,
+        &CoupleWithRapierContext,
+        Option<&SalvaRapierCoupling>,
+    )>,
+    rapier_contexts: Query<(
+        Entity,
+        &RapierConfiguration,
+        Option<&Name>,
+        Has<DefaultRapierContext>,
+    )>,
+    boundaries: Query<(Entity, &ColliderBoundaryHandle, &SalvaContextEntityLink)>,
+) {
+    for (salva_context_entity, mut context, mut config, couple_with, coupling) in
+        salva_contexts.iter_mut()
+    {
+        let target = rapier_contexts
+            .iter()
+            .find(|(entity, _, name, is_default)| match couple_with {
+                CoupleWithRapierContext::Default => *is_default,
+                CoupleWithRapierContext::Entity(target) => entity == target,
+                CoupleWithRapierContext::Named(target) => {
+                    name.is_some_and(|name| name.as_str() == target)
+                }
+            });
+        let is_coupled = coupling.is_some_and(|coupling| {
+            target.is_some_and(|(entity, ..)| entity == coupling.rapier_context_entity)
+        });
+        if is_coupled {
+            continue;
+        }
+
+        if coupling.is_some() {
+            for (collider_entity, boundary_handle, link) in boundaries.iter() {
+                if link.0 == salva_context_entity {
+                    context.liquid_world.remove_boundary(boundary_handle.0);
+                    context.coupled_boundaries.remove(&boundary_handle.0);
+                    commands
+                        .entity(collider_entity)
+                        .remove::<ColliderBoundaryHandle>();
+                }
+            }
+            commands
+                .entity(salva_context_entity)
+                .remove::<SalvaRapierCoupling>();
+        }
+
+        let Some((rapier_context_entity, rapier_config, ..)) = target else {
+            continue;
+        };
+        commands
+            .entity(salva_context_entity)
+            .insert(SalvaRapierCoupling {
+                rapier_context_entity,
+                coupling: ColliderCouplingSet::new(),
+            });
+        config.gravity = rapier_config.gravity;
+        config.physics_pipeline_active = None;
+    }
+}
+
+/// The system that applies [`AnalyticBuoyancy`] forces to the rigid bodies of coupled colliders.
+///
+/// Forces are applied as impulses over the time the salva context was just stepped by (see
+/// [`SalvaStepStats::simulated_time`]), so they take effect on the next Rapier step. Nothing is
+/// applied during the ticks where the salva context isn't stepped.
+pub fn apply_analytic_buoyancy(
+    colliders: Query<(
+        &AnalyticBuoyancy,
+        &RapierColliderHandle,
+        &RapierContextEntityLink,
+        Option<&SalvaContextEntityLink>,
+    )>,
+    salva_contexts: Query<(&SalvaContext, &SalvaConfiguration, &SalvaStepStats)>,
+    q_default_context: Query<Entity, With<DefaultSalvaContext>>,
+    rapier_configs: Query<&RapierConfiguration>,
+    mut write_rapier_context: WriteRapierContext<()>,
+) {
+    for (buoyancy, co_handle, rapier_link, salva_link) in colliders.iter() {
+        let Some(salva_context_entity) = salva_link
+            .map(|link| link.0)
+            .or_else(|| q_default_context.single().ok())
+        else {
+            continue;
+        };
+        let Ok((context, config, stats)) = salva_contexts.get(salva_context_entity) else {
+            continue;
+        };
+        let dt = stats.simulated_time;
+        if dt <= 0.0 {
+            continue;
+        }
+        if !rapier_configs
+            .get(rapier_link.0)
+            .is_ok_and(|rapier_config| rapier_config.physics_pipeline_active)
+        {
+            continue;
+        }
+        let Ok((_, colliders, _, _, mut rigidbody_set)) =
+            write_rapier_context.rapier_context.get_mut(rapier_link.0)
+        else {
+            continue;
+        };
+
+        let Some(co) = colliders.colliders.get(co_handle.0) else {
+            continue;
+        };
+        let Some(body) = co.parent().and_then(|h| rigidbody_set.bodies.get_mut(h)) else {
+            continue;
+        };
+
+        let h = context.liquid_world.h();
+        let index = context.particle_index();
+
+        let spacing = buoyancy
+            .sample_spacing
+            .unwrap_or(context.liquid_world.particle_radius() * 2.0);
+        #[cfg(feature = "dim2")]
+        let sample_volume = spacing * spacing;
+        #[cfg(feature = "dim3")]
+        let sample_volume = spacing * spacing * spacing;
+        let aabb = co.compute_aabb();
+
+        for sample in lattice_points(Vect::from(aabb.mins), Vect::from(aabb.maxs), spacing) {
+            let point = Point::from(sample);
+            if !co.shape().contains_point(co.position(), &point) {
+                continue;
+            }
+
+            // Shepard-normalized estimates of the fluid around the sample point.
+            let mut fraction = 0.0;
+            let mut density = 0.0;
+            let mut fluid_velocity = Vect::ZERO;
+            for (particle_pos, (fluid_handle, i)) in index.fluid_particles_in_radius(sample, h) {
+                let Some(fluid) = context.liquid_world.fluids().get(*fluid_handle) else {
+                    continue;
+                };
+                if *i >= fluid.num_particles() {
+                    continue;
+                }
+                let weight =
+                    CubicSplineKernel::scalar_apply(particle_pos.distance(sample), h) * fluid.volumes[*i];
+                fraction += weight;
+                density += weight * fluid.density0;
+                fluid_velocity += Vect::from(fluid.velocities[*i]) * weight;
+            }
+            if fraction <= 0.0 {
+                continue;
+            }
+            density /= fraction;
+            fluid_velocity /= fraction;
+            let submerged_volume = sample_volume * fraction.min(1.0);
+
+            let buoyancy_force =
+                -config.gravity * density * submerged_volume * buoyancy.buoyancy_scale;
+            let relative_velocity = Vect::from(body.velocity_at_point(&point)) - fluid_velocity;
+            let drag_force = -relative_velocity
+                * (buoyancy.linear_drag
+                    + buoyancy.quadratic_drag * relative_velocity.length())
+                * submerged_volume;
+
+            body.apply_impulse_at_point(((buoyancy_force + drag_force) * dt).into(), point, true);
         }
     }
 }
 
+#[cfg(feature = "dim2")]
+fn lattice_points(mins: Vect, maxs: Vect, spacing: Real) -> impl Iterator<Item = Vect> {
+    let counts = ((maxs - mins) / spacing).ceil().max(Vect::ONE).as_uvec2();
+    (0..counts.x).flat_map(move |i| {
+        (0..counts.y).map(move |j| mins + (Vect::new(i as Real, j as Real) + 0.5) * spacing)
+    })
+}
+
+#[cfg(feature = "dim3")]
+fn lattice_points(mins: Vect, maxs: Vect, spacing: Real) -> impl Iterator<Item = Vect> {
+    let counts = ((maxs - mins) / spacing).ceil().max(Vect::ONE).as_uvec3();
+    (0..counts.x).flat_map(move |i| {
+        (0..counts.y).flat_map(move |j| {
+            (0..counts.z).map(move |k| {
+                mins + (Vect::new(i as Real, j as Real, k as Real) + 0.5) * spacing
+            })
+        })
+    })
+}
+
 /// The system responsible for sampling/coupling rapier colliders for rapier-salva coupling
 /// by converting them into fluid boundaries.
 pub fn sample_rapier_colliders(
@@ -154,9 +467,17 @@ pub fn sample_rapier_colliders(
             |link| *link,
         );
 
+        // Colliders are sampled once their SalvaContext is coupled with their RapierContext.
+        let Ok(mut rapier_coupling) = rapier_coupling_q.get_mut(salva_link.0) else {
+            continue;
+        };
+        if rapier_coupling.rapier_context_entity != rapier_link.0 {
+            continue;
+        }
+        let coupling = &mut rapier_coupling.coupling;
+
         let mut salva_context = context_writer.context(&salva_link);
         let radius = salva_context.liquid_world.particle_radius();
-        let coupling = &mut rapier_coupling_q.get_mut(salva_link.0).unwrap().coupling;
 
         let (_, mut colliders, _, _, _) = rapier_context_access
             .rapier_context
@@ -176,6 +497,7 @@ pub fn sample_rapier_colliders(
                     .unwrap(),
             }),
         ));
+        salva_context.coupled_boundaries.insert(bo_handle);
         coupling.register_coupling(
             bo_handle,
             co_handle.0,
@@ -196,16 +518,23 @@ pub fn sample_rapier_colliders(
     }
 }
 
-/// System that links the default salva context to the default rapier context
+/// System that couples the default salva context with the default rapier context, through
+/// [`CoupleWithRapierContext::Default`].
+///
+/// This also runs for default salva contexts spawned later on, e.g. after the default one was
+/// respawned.
 #[cfg(feature = "rapier")]
 pub fn link_default_contexts(
     mut commands: Commands,
     initialization_data: Res<SalvaContextInitialization>,
-    mut default_salva_context: Query<
-        (Entity, &mut SalvaConfiguration),
-        (With<DefaultSalvaContext>, Without<SalvaRapierCoupling>),
+    default_salva_context: Query<
+        Entity,
+        (
+            With<DefaultSalvaContext>,
+            Without<CoupleWithRapierContext>,
+            Without<SalvaRapierCoupling>,
+        ),
     >,
-    default_rapier_context: Query<(Entity, &RapierConfiguration), With<DefaultRapierContext>>,
 ) {
     match initialization_data.as_ref() {
         SalvaContextInitialization::NoAutomaticSalvaContext => {}
@@ -213,17 +542,10 @@ pub fn link_default_contexts(
             particle_radius: _particle_radius,
             smoothing_factor: _smoothing_factor,
         } => {
-            if let Ok((salva_context_entity, mut salva_config)) = default_salva_context.single_mut() {
-                if let Ok((rapier_context_entity, rapier_config)) = default_rapier_context.single()
-                {
-                    commands
-                        .entity(salva_context_entity)
-                        .insert(SalvaRapierCoupling {
-                            rapier_context_entity,
-                            coupling: ColliderCouplingSet::new(),
-                        });
-                    salva_config.gravity = rapier_config.gravity;
-                }
+            for salva_context_entity in default_salva_context.iter() {
+                commands
+                    .entity(salva_context_entity)
+                    .insert(CoupleWithRapierContext::Default);
             }
         }
     }
@@ -0,0 +1,651 @@
+//! Recording of fluid particle positions to a compact binary format, and playback of such
+//! recordings.
+//!
+//! A recording is a header followed by one frame per simulation step:
+//! - header: the magic bytes `SLVR`, the format version (`u8`), the number of dimensions (`u8`)
+//!   and the quantization step (`f32`).
+//! - frame: the duration of the step (`f32`), the number of tracks, then for each track its id,
+//!   whether it is a keyframe (`u8`), its number of particles and its quantized coordinates.
+//!
+//! Coordinates are quantized to multiples of the quantization step. If a track has as many
+//! particles as in its previous frame, its coordinates are stored as differences with the
+//! previous frame, otherwise as a keyframe. Integers are zigzag-encoded LEB128 varints, and
+//! floats are little-endian.
+
+use crate::fluid::{FluidPositions, SalvaFluidHandle};
+use crate::math::{Real, Vect};
+use crate::plugin::{TimestepMode, WriteSalvaContext};
+use bevy::prelude::*;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+const MAGIC: &[u8; 4] = b"SLVR";
+const VERSION: u8 = 1;
+#[cfg(feature = "dim2")]
+const DIM: usize = 2;
+#[cfg(feature = "dim3")]
+const DIM: usize = 3;
+/// The largest number of coordinates of a track allocated before they are read, so that a corrupt
+/// particle count can't allocate more than the recording contains.
+const MAX_PREALLOCATED_COORDS: usize = 1 << 16;
+
+/// Encodes frames of particle positions.
+#[derive(Clone, Debug)]
+pub struct FluidRecordingEncoder {
+    quantization: Real,
+    previous: HashMap<u32, Vec<i64>>,
+}
+
+impl FluidRecordingEncoder {
+    /// Creates an encoder quantizing coordinates to multiples of `quantization`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `quantization` isn't positive.
+    pub fn new(quantization: Real) -> Self {
+        assert!(
+            quantization > 0.0,
+            "the quantization step of a fluid recording must be positive"
+        );
+        Self {
+            quantization,
+            previous: HashMap::new(),
+        }
+    }
+
+    /// Writes the header of a recording.
+    pub fn write_header(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION, DIM as u8])?;
+        out.write_all(&(self.quantization as f32).to_le_bytes())
+    }
+
+    /// Writes a frame lasting `dt`, with the positions of each track.
+    pub fn write_frame<'a>(
+        &mut self,
+        dt: Real,
+        tracks: impl ExactSizeIterator<Item = (u32, &'a [Vect])>,
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        out.write_all(&(dt as f32).to_le_bytes())?;
+        write_varint(out, tracks.len() as u64)?;
+
+        for (track, positions) in tracks {
+            let quantized: Vec<i64> = positions
+                .iter()
+                .flat_map(|pos| pos.to_array())
+                .map(|x| (x / self.quantization).round() as i64)
+                .collect();
+            let previous = self
+                .previous
+                .get(&track)
+                .filter(|previous| previous.len() == quantized.len());
+
+            write_varint(out, track as u64)?;
+            out.write_all(&[previous.is_none() as u8])?;
+            write_varint(out, positions.len() as u64)?;
+            match previous {
+                Some(previous) => {
+                    for (q, prev) in quantized.iter().zip(previous.iter()) {
+                        write_varint(out, zigzag(q - prev))?;
+                    }
+                }
+                None => {
+                    for q in quantized.iter() {
+                        write_varint(out, zigzag(*q))?;
+                    }
+                }
+            }
+
+            self.previous.insert(track, quantized);
+        }
+
+        Ok(())
+    }
+}
+
+/// One decoded frame of a [`FluidRecording`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FluidRecordingFrame {
+    /// The duration of the simulation step of this frame.
+    pub dt: Real,
+    /// The particle positions of each track.
+    pub tracks: HashMap<u32, Vec<Vect>>,
+}
+
+/// A decoded recording of fluid particle positions. See the [module docs](self) for the format.
+///
+/// This holds every frame in memory, use a [`FluidRecordingDecoder`] to go through long
+/// recordings frame by frame.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FluidRecording {
+    pub quantization: Real,
+    pub frames: Vec<FluidRecordingFrame>,
+}
+
+impl FluidRecording {
+    /// Reads a recording from a file.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    /// Reads a recording.
+    pub fn read(reader: impl Read) -> io::Result<Self> {
+        let decoder = FluidRecordingDecoder::new(reader)?;
+        let quantization = decoder.quantization();
+        let frames = decoder.collect::<io::Result<_>>()?;
+        Ok(Self { quantization, frames })
+    }
+
+    /// The positions of the particles of `track` at `frame`.
+    pub fn positions(&self, frame: usize, track: u32) -> Option<&[Vect]> {
+        self.frames.get(frame)?.tracks.get(&track).map(|positions| positions.as_slice())
+    }
+}
+
+/// Decodes the frames of a recording one at a time. See the [module docs](self) for the format.
+///
+/// Frames are read as the decoder is iterated. A recording that ends in the middle of a frame
+/// yields an [`io::ErrorKind::UnexpectedEof`] error.
+pub struct FluidRecordingDecoder<R> {
+    reader: R,
+    quantization: Real,
+    previous: HashMap<u32, Vec<i64>>,
+}
+
+impl FluidRecordingDecoder<BufReader<File>> {
+    /// Reads the header of the recording in a file.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> FluidRecordingDecoder<R> {
+    /// Reads the header of a recording.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0; 6];
+        reader.read_exact(&mut header)?;
+        if &header[..4] != MAGIC {
+            return Err(invalid_data("not a fluid recording"));
+        }
+        if header[4] != VERSION {
+            return Err(invalid_data("unsupported fluid recording version"));
+        }
+        if header[5] as usize != DIM {
+            return Err(invalid_data("fluid recording has the wrong number of dimensions"));
+        }
+        let quantization = read_f32(&mut reader)? as Real;
+        if quantization.is_nan() || quantization <= 0.0 {
+            return Err(invalid_data("fluid recording has a non-positive quantization step"));
+        }
+
+        Ok(Self {
+            reader,
+            quantization,
+            previous: HashMap::new(),
+        })
+    }
+
+    /// The quantization step of the recording.
+    pub fn quantization(&self) -> Real {
+        self.quantization
+    }
+
+    /// Decodes the next frame, or returns `None` at the end of the recording.
+    pub fn read_frame(&mut self) -> io::Result<Option<FluidRecordingFrame>> {
+        // The recording ends where a frame would start.
+        let mut dt = [0; 4];
+        match read_up_to(&mut self.reader, &mut dt)? {
+            0 => return Ok(None),
+            4 => {}
+            _ => return Err(truncated()),
+        }
+        let dt = f32::from_le_bytes(dt) as Real;
+        let tracks = self.read_tracks().map_err(eof_as_truncated)?;
+
+        Ok(Some(FluidRecordingFrame { dt, tracks }))
+    }
+
+    fn read_tracks(&mut self) -> io::Result<HashMap<u32, Vec<Vect>>> {
+        let reader = &mut self.reader;
+        let track_count = read_varint(reader)?;
+        let mut tracks = HashMap::new();
+        for _ in 0..track_count {
+            let track = read_varint(reader)? as u32;
+            let mut keyframe = [0];
+            reader.read_exact(&mut keyframe)?;
+            let coord_count = usize::try_from(read_varint(reader)?)
+                .ok()
+                .and_then(|particle_count| particle_count.checked_mul(DIM))
+                .ok_or_else(|| invalid_data("fluid recording particle count is too large"))?;
+
+            let mut quantized = Vec::with_capacity(coord_count.min(MAX_PREALLOCATED_COORDS));
+            match self.previous.get(&track).filter(|_| keyframe[0] == 0) {
+                Some(prev) if prev.len() == coord_count => {
+                    for prev in prev.iter() {
+                        quantized.push(prev + unzigzag(read_varint(reader)?));
+                    }
+                }
+                Some(_) => return Err(invalid_data("fluid recording delta without a matching frame")),
+                None => {
+                    for _ in 0..coord_count {
+                        quantized.push(unzigzag(read_varint(reader)?));
+                    }
+                }
+            }
+
+            let positions = quantized
+                .chunks_exact(DIM)
+                .map(|coords| {
+                    Vect::from_array(std::array::from_fn(|axis| coords[axis] as Real * self.quantization))
+                })
+                .collect();
+            tracks.insert(track, positions);
+            self.previous.insert(track, quantized);
+        }
+
+        Ok(tracks)
+    }
+}
+
+impl<R: Read> Iterator for FluidRecordingDecoder<R> {
+    type Item = io::Result<FluidRecordingFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_frame().transpose()
+    }
+}
+
+/// Records the particle positions of fluid entities after each simulation step.
+///
+/// The track of each fluid is its index in [`Self::fluids`]. Recording stops if writing fails.
+/// Call [`Self::flush`] to make sure everything recorded so far is written; this is also done
+/// when the recorder is dropped.
+#[derive(Component)]
+pub struct FluidRecorder {
+    /// The recorded fluid entities.
+    pub fluids: Vec<Entity>,
+    encoder: FluidRecordingEncoder,
+    writer: Option<Box<dyn Write + Send + Sync>>,
+}
+
+impl FluidRecorder {
+    /// Starts recording `fluids` to `writer`, quantizing coordinates to multiples of
+    /// `quantization`, which must be positive.
+    pub fn new(
+        writer: impl Write + Send + Sync + 'static,
+        fluids: Vec<Entity>,
+        quantization: Real,
+    ) -> io::Result<Self> {
+        if quantization.is_nan() || quantization <= 0.0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the quantization step of a fluid recording must be positive",
+            ));
+        }
+        let mut writer: Box<dyn Write + Send + Sync> = Box::new(writer);
+        let encoder = FluidRecordingEncoder::new(quantization);
+        encoder.write_header(&mut writer)?;
+        Ok(Self {
+            fluids,
+            encoder,
+            writer: Some(writer),
+        })
+    }
+
+    /// Starts recording `fluids` to a new file at `path`.
+    pub fn create(path: impl AsRef<Path>, fluids: Vec<Entity>, quantization: Real) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), fluids, quantization)
+    }
+
+    /// Is this still recording?
+    pub fn is_recording(&self) -> bool {
+        self.writer.is_some()
+    }
+
+    /// Flushes the recorded frames.
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.writer {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for FluidRecorder {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            error!("Failed to flush the fluid recording: {err}");
+        }
+    }
+}
+
+/// Plays a [`FluidRecording`] by writing the positions of one of its tracks to the
+/// [`FluidPositions`] of this entity.
+///
+/// Entities with a player aren't simulated: if the entity already had a salva fluid, it is
+/// removed from its [`SalvaContext`](crate::plugin::SalvaContext) when the player is added.
+#[derive(Component, Clone, Debug)]
+#[require(FluidPositions)]
+pub struct FluidPlayer {
+    pub recording: Arc<FluidRecording>,
+    pub track: u32,
+    /// The playback speed (default 1.0).
+    pub speed: Real,
+    /// Starts over once the last frame is reached.
+    pub looping: bool,
+    pub paused: bool,
+    frame: usize,
+    elapsed: Real,
+    started: bool,
+}
+
+impl FluidPlayer {
+    pub fn new(recording: Arc<FluidRecording>, track: u32) -> Self {
+        Self {
+            recording,
+            track,
+            speed: 1.0,
+            looping: false,
+            paused: false,
+            frame: 0,
+            elapsed: 0.0,
+            started: false,
+        }
+    }
+
+    pub fn looping(mut self) -> Self {
+        self.looping = true;
+        self
+    }
+
+    /// The frame currently shown.
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    /// Shows `frame` next.
+    pub fn seek(&mut self, frame: usize) {
+        self.frame = frame.min(self.recording.frames.len().saturating_sub(1));
+        self.elapsed = 0.0;
+        self.started = false;
+    }
+
+    /// Has the last frame been reached?
+    pub fn is_finished(&self) -> bool {
+        !self.looping && self.frame + 1 >= self.recording.frames.len()
+    }
+}
+
+/// Removes the salva fluid of the entities that were given a [`FluidPlayer`], so that the
+/// simulation doesn't overwrite the played positions.
+pub fn detach_played_fluids(
+    mut commands: Commands,
+    players: Query<(Entity, &SalvaFluidHandle), With<FluidPlayer>>,
+    mut context_writer: WriteSalvaContext,
+) {
+    for (entity, handle) in players.iter() {
+        for mut context in context_writer.salva_context.iter_mut() {
+            if context.entity2fluid.get(&entity) == Some(&handle.0) {
+                context.entity2fluid.remove(&entity);
+                context.liquid_world.remove_fluid(handle.0);
+                context.nonpressure_force_configs.remove(&handle.0);
+            }
+        }
+        commands.entity(entity).remove::<SalvaFluidHandle>();
+    }
+}
+
+/// Writes a frame for each [`FluidRecorder`] whose fluids were updated.
+pub fn record_fluids(
+    mut recorders: Query<&mut FluidRecorder>,
+    fluids: Query<Ref<FluidPositions>>,
+    timestep_mode: Res<TimestepMode>,
+    time: Res<Time>,
+) {
+    let dt = timestep_mode.delta(&time);
+
+    for mut recorder in recorders.iter_mut() {
+        let recorder = &mut *recorder;
+        let Some(writer) = &mut recorder.writer else {
+            continue;
+        };
+
+        let tracks: Vec<(u32, &[Vect])> = recorder
+            .fluids
+            .iter()
+            .enumerate()
+            .filter_map(|(track, entity)| {
+                let positions = fluids.get(*entity).ok()?;
+                Some((track as u32, positions))
+            })
+            .filter(|(_, positions)| positions.is_changed())
+            .map(|(track, positions)| (track, positions.into_inner().as_slice()))
+            .collect();
+        if tracks.is_empty() {
+            continue;
+        }
+
+        if let Err(err) = recorder.encoder.write_frame(dt, tracks.into_iter(), writer) {
+            error!("Fluid recording stopped: {err}");
+            recorder.writer = None;
+        }
+    }
+}
+
+/// Advances the [`FluidPlayer`]s and writes their current frame to [`FluidPositions`].
+pub fn play_fluid_recordings(
+    mut players: Query<(&mut FluidPlayer, &mut FluidPositions)>,
+    time: Res<Time>,
+) {
+    for (mut player, mut positions) in players.iter_mut() {
+        let frame_count = player.recording.frames.len();
+        if frame_count == 0 {
+            continue;
+        }
+
+        let previous_frame = player.frame;
+        if player.started && !player.paused {
+            player.elapsed += time.delta_secs() * player.speed;
+            loop {
+                let dt = player.recording.frames[player.frame].dt;
+                if dt <= 0.0 || player.elapsed < dt {
+                    break;
+                }
+                if player.frame + 1 < frame_count {
+                    player.frame += 1;
+                } else if player.looping {
+                    player.frame = 0;
+                } else {
+                    player.elapsed = 0.0;
+                    break;
+                }
+                player.elapsed -= dt;
+            }
+        }
+
+        if !player.started || player.frame != previous_frame {
+            player.started = true;
+            if let Some(frame_positions) = player.recording.positions(player.frame, player.track) {
+                positions.0 = frame_positions.to_vec();
+            }
+        }
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "fluid recording ends in the middle of a frame",
+    )
+}
+
+fn eof_as_truncated(err: io::Error) -> io::Error {
+    if err.kind() == io::ErrorKind::UnexpectedEof {
+        truncated()
+    } else {
+        err
+    }
+}
+
+/// Fills `buf` from `reader` until it ends, returning the number of bytes read.
+fn read_up_to(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(read)
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+fn write_varint(out: &mut impl Write, mut value: u64) -> io::Result<()> {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            return out.write_all(&[byte]);
+        }
+        out.write_all(&[byte | 0x80])?;
+    }
+}
+
+fn read_varint(reader: &mut impl Read) -> io::Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        reader.read_exact(&mut byte)?;
+        value |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid_data("fluid recording varint is too long"))
+}
+
+fn read_f32(reader: &mut impl Read) -> io::Result<f32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(f32::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(quantization: Real, frames: &[Vec<(u32, Vec<Vect>)>]) -> Vec<u8> {
+        let mut encoder = FluidRecordingEncoder::new(quantization);
+        let mut bytes = Vec::new();
+        encoder.write_header(&mut bytes).unwrap();
+        for tracks in frames {
+            let tracks = tracks.iter().map(|(track, positions)| (*track, positions.as_slice()));
+            encoder.write_frame(1.0 / 60.0, tracks, &mut bytes).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        for value in [0, 1, -1, 63, -64, 64, 1 << 40, -(1 << 40), i64::MAX, i64::MIN] {
+            let mut bytes = Vec::new();
+            write_varint(&mut bytes, zigzag(value)).unwrap();
+            assert_eq!(unzigzag(read_varint(&mut bytes.as_slice()).unwrap()), value);
+        }
+    }
+
+    #[test]
+    fn frames_round_trip_through_keyframes_and_deltas() {
+        let frames = vec![
+            vec![(0, vec![Vect::splat(0.5), Vect::splat(-1.25)]), (3, vec![Vect::X])],
+            // Same particle count: stored as deltas.
+            vec![(0, vec![Vect::splat(0.75), Vect::splat(-1.5)]), (3, vec![Vect::X * 2.0])],
+            // New particle count: stored as a keyframe.
+            vec![(0, vec![Vect::splat(1.0)])],
+        ];
+        let recording = FluidRecording::read(encode(0.25, &frames).as_slice()).unwrap();
+
+        assert_eq!(recording.quantization, 0.25);
+        assert_eq!(recording.frames.len(), frames.len());
+        for (i, tracks) in frames.iter().enumerate() {
+            assert_eq!(recording.frames[i].tracks.len(), tracks.len());
+            for (track, positions) in tracks {
+                assert_eq!(recording.positions(i, *track), Some(positions.as_slice()));
+            }
+        }
+    }
+
+    #[test]
+    fn positions_are_quantized() {
+        let frames = vec![vec![(0, vec![Vect::splat(0.26)])]];
+        let recording = FluidRecording::read(encode(0.25, &frames).as_slice()).unwrap();
+        assert_eq!(recording.positions(0, 0), Some([Vect::splat(0.25)].as_slice()));
+    }
+
+    #[test]
+    fn truncated_frames_are_unexpected_eof() {
+        let frames = vec![vec![(0, vec![Vect::splat(0.5)])], vec![(0, vec![Vect::splat(1.0)])]];
+        let bytes = encode(0.25, &frames);
+        let header_len = 10;
+        let first_frame_end = encode(0.25, &frames[..1]).len();
+
+        for len in (header_len + 1..bytes.len()).filter(|len| *len != first_frame_end) {
+            let mut decoder = FluidRecordingDecoder::new(&bytes[..len]).unwrap();
+            let err = decoder
+                .by_ref()
+                .find_map(Result::err)
+                .unwrap_or_else(|| panic!("a recording truncated to {len} bytes was decoded"));
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        }
+        assert_eq!(FluidRecording::read(bytes.as_slice()).unwrap().frames.len(), 2);
+    }
+
+    #[test]
+    fn corrupt_particle_counts_are_rejected() {
+        // A frame with a single keyframe track, and the given particle count.
+        let frame = |particle_count: u64| {
+            let mut bytes = encode(0.25, &[]);
+            bytes.extend_from_slice(&(1.0f32 / 60.0).to_le_bytes());
+            for value in [1, 0] {
+                write_varint(&mut bytes, value).unwrap();
+            }
+            bytes.push(1);
+            write_varint(&mut bytes, particle_count).unwrap();
+            bytes
+        };
+
+        let err = FluidRecording::read(frame(u64::MAX).as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // Counts larger than the recording fail when the coordinates run out, without allocating
+        // them all upfront.
+        let err = FluidRecording::read(frame(1 << 40).as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn non_positive_quantization_is_rejected() {
+        assert!(FluidRecorder::new(Vec::new(), Vec::new(), 0.0).is_err());
+
+        let mut bytes = encode(0.25, &[]);
+        bytes[6..10].copy_from_slice(&0.0f32.to_le_bytes());
+        let err = FluidRecording::read(bytes.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
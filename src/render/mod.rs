@@ -0,0 +1,212 @@
+use crate::fluid::FluidDensities;
+use crate::math::{Real, Vect};
+use crate::plugin::{SalvaContext, SalvaSimulationSet};
+use bevy::prelude::*;
+
+pub use self::particles::*;
+
+mod particles;
+
+/// Plugin that draws the fluid particles and boundary samples of every [`SalvaContext`] with
+/// [`Gizmos`]: circles in 2D, spheres in 3D.
+///
+/// What is drawn for each context is configured by the [`SalvaDebugRenderSettings`] on the
+/// context entity, falling back to [`SalvaDebugRenderContext::default_settings`].
+pub struct SalvaDebugRenderPlugin {
+    /// Is the debug-rendering enabled?
+    pub enabled: bool,
+    /// The settings used for [`SalvaContext`] entities without [`SalvaDebugRenderSettings`].
+    pub default_settings: SalvaDebugRenderSettings,
+}
+
+impl Default for SalvaDebugRenderPlugin {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            default_settings: SalvaDebugRenderSettings::default(),
+        }
+    }
+}
+
+impl SalvaDebugRenderPlugin {
+    /// Debug-render with the given fluid particle coloring.
+    pub fn with_color_mode(mut self, color_mode: FluidDebugColorMode) -> Self {
+        self.default_settings.color_mode = color_mode;
+        self
+    }
+
+    /// Disables the debug-rendering until [`SalvaDebugRenderContext::enabled`] is set to `true`.
+    pub fn disabled(mut self) -> Self {
+        self.enabled = false;
+        self
+    }
+}
+
+impl Plugin for SalvaDebugRenderPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<SalvaDebugRenderContext>()
+            .register_type::<SalvaDebugRenderSettings>()
+            .register_type::<FluidDebugColor>()
+            .insert_resource(SalvaDebugRenderContext {
+                enabled: self.enabled,
+                default_settings: self.default_settings.clone(),
+            })
+            .add_systems(
+                PostUpdate,
+                debug_render_scene
+                    .after(SalvaSimulationSet::Writeback)
+                    .after(TransformSystem::TransformPropagate)
+                    .run_if(|ctx: Res<SalvaDebugRenderContext>| ctx.enabled),
+            );
+    }
+}
+
+/// Context to control the debug-rendering of fluids.
+#[derive(Resource, Reflect, Clone, Debug)]
+#[reflect(Resource)]
+pub struct SalvaDebugRenderContext {
+    /// Is the debug-rendering enabled?
+    pub enabled: bool,
+    /// The settings used for [`SalvaContext`] entities without [`SalvaDebugRenderSettings`].
+    pub default_settings: SalvaDebugRenderSettings,
+}
+
+/// Add this to a [`SalvaContext`] entity to control how its fluids are debug-rendered.
+#[derive(Component, Reflect, Clone, Debug, PartialEq)]
+#[reflect(Component)]
+pub struct SalvaDebugRenderSettings {
+    /// Is the debug-rendering of this context enabled?
+    pub enabled: bool,
+    /// Should fluid particles be drawn?
+    pub draw_particles: bool,
+    /// Should the sample points of fluid boundaries (e.g. sampled Rapier colliders) be drawn?
+    pub draw_boundaries: bool,
+    /// How fluid particles are colored.
+    pub color_mode: FluidDebugColorMode,
+    /// The color of boundary sample points.
+    pub boundary_color: Color,
+}
+
+impl Default for SalvaDebugRenderSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            draw_particles: true,
+            draw_boundaries: true,
+            color_mode: FluidDebugColorMode::Fluid,
+            boundary_color: Color::srgb(0.9, 0.6, 0.2),
+        }
+    }
+}
+
+/// How fluid particles are colored by the [`SalvaDebugRenderPlugin`].
+#[derive(Copy, Clone, Debug, PartialEq, Reflect)]
+pub enum FluidDebugColorMode {
+    /// Each fluid gets its [`FluidDebugColor`], or a distinct color if it has none.
+    Fluid,
+    /// Particles are colored from blue (at rest) to red (at `max_speed` or faster).
+    Velocity { max_speed: Real },
+    /// Particles are colored from blue (at `min` or lower) to red (at `max` or higher) based on
+    /// their [`FluidDensities`], which are added to the fluid entities that don't have them.
+    Density { min: Real, max: Real },
+}
+
+/// Add this to a fluid entity to choose its color when debug-rendered with
+/// [`FluidDebugColorMode::Fluid`].
+#[derive(Component, Reflect, Copy, Clone, Debug, PartialEq)]
+#[reflect(Component)]
+pub struct FluidDebugColor(pub Color);
+
+/// The color of a value on the blue-to-red gradient used by the [`SalvaDebugRenderPlugin`].
+pub fn gradient_color(t: Real) -> Color {
+    let t = t.clamp(0.0, 1.0);
+    Color::hsl((1.0 - t) * 240.0, 0.8, 0.5)
+}
+
+/// The color of `value` on the gradient from `min` to `max`. If the range is empty, values below
+/// `max` are blue and the others red.
+fn gradient_color_in(value: Real, min: Real, max: Real) -> Color {
+    if max > min {
+        gradient_color((value - min) / (max - min))
+    } else {
+        gradient_color(if value < max { 0.0 } else { 1.0 })
+    }
+}
+
+fn fluid_color(index: usize) -> Color {
+    Color::hsl((index as f32 * 137.5) % 360.0, 0.7, 0.55)
+}
+
+/// The system that draws every [`SalvaContext`] with [`Gizmos`].
+pub fn debug_render_scene(
+    mut commands: Commands,
+    mut gizmos: Gizmos,
+    render_context: Res<SalvaDebugRenderContext>,
+    salva_contexts: Query<(&SalvaContext, Option<&SalvaDebugRenderSettings>)>,
+    fluids: Query<(Option<&FluidDebugColor>, Option<&FluidDensities>)>,
+) {
+    for (context, settings) in salva_contexts.iter() {
+        let settings = settings.unwrap_or(&render_context.default_settings);
+        if !settings.enabled {
+            continue;
+        }
+
+        let radius = context.liquid_world.particle_radius();
+
+        if settings.draw_particles {
+            let fluid2entity = context.fluid2entity();
+
+            for (i, (handle, fluid)) in context.liquid_world.fluids().iter().enumerate() {
+                let entity = fluid2entity.get(&handle).copied();
+                let (debug_color, densities) = entity
+                    .and_then(|entity| fluids.get(entity).ok())
+                    .unwrap_or_default();
+                let base_color = debug_color.map_or_else(|| fluid_color(i), |color| color.0);
+                if let (FluidDebugColorMode::Density { .. }, Some(entity), None) =
+                    (settings.color_mode, entity, densities)
+                {
+                    commands.entity(entity).insert(FluidDensities::default());
+                }
+
+                for (j, pos) in fluid.positions.iter().enumerate() {
+                    let color = match settings.color_mode {
+                        FluidDebugColorMode::Fluid => base_color,
+                        FluidDebugColorMode::Velocity { max_speed } => {
+                            gradient_color_in(fluid.velocities[j].norm(), 0.0, max_speed)
+                        }
+                        FluidDebugColorMode::Density { min, max } => {
+                            let density = densities
+                                .and_then(|densities| densities.get(j).copied())
+                                .unwrap_or(min);
+                            gradient_color_in(density, min, max)
+                        }
+                    };
+                    draw_point(&mut gizmos, Vect::from(*pos), radius, color);
+                }
+            }
+        }
+
+        if settings.draw_boundaries {
+            for (_, boundary) in context.liquid_world.boundaries().iter() {
+                for pos in boundary.positions.iter() {
+                    draw_point(
+                        &mut gizmos,
+                        Vect::from(*pos),
+                        radius * 0.5,
+                        settings.boundary_color,
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "dim2")]
+fn draw_point(gizmos: &mut Gizmos, pos: Vect, radius: Real, color: Color) {
+    gizmos.circle_2d(pos, radius, color);
+}
+
+#[cfg(feature = "dim3")]
+fn draw_point(gizmos: &mut Gizmos, pos: Vect, radius: Real, color: Color) {
+    gizmos.sphere(pos, radius, color);
+}
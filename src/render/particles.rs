@@ -0,0 +1,447 @@
+use super::{fluid_color, gradient_color_in, FluidDebugColor, FluidDebugColorMode};
+use crate::fluid::{FluidDensities, FluidPositions, FluidVelocities, SalvaFluidHandle};
+use crate::math::{Real, Vect};
+use crate::plugin::{SalvaContext, SalvaContextEntityLink, SalvaSimulationSet};
+use bevy::asset::RenderAssetUsages;
+use bevy::math::Affine3A;
+use bevy::prelude::*;
+use bevy::render::mesh::{Indices, PrimitiveTopology, VertexAttributeValues};
+
+/// The material of the mesh of a [`FluidParticleMesh`].
+#[cfg(feature = "dim2")]
+pub type FluidParticleMaterial = ColorMaterial;
+/// The material of the mesh of a [`FluidParticleMesh`].
+#[cfg(feature = "dim3")]
+pub type FluidParticleMaterial = StandardMaterial;
+
+/// Plugin that renders the particles of the fluids with a [`FluidParticleMesh`].
+pub struct SalvaParticleMeshPlugin;
+
+impl Plugin for SalvaParticleMeshPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<FluidParticleMesh>().add_systems(
+            PostUpdate,
+            update_fluid_particle_meshes
+                .after(SalvaSimulationSet::Writeback)
+                .after(TransformSystem::TransformPropagate),
+        );
+    }
+}
+
+/// Add this to a fluid entity to render its particles as a single merged mesh, made of one copy
+/// of a template shape per particle with a per-particle vertex color.
+///
+/// This isn't GPU instancing: whenever the particles move, the template is copied for every
+/// particle on the CPU and the whole mesh is uploaded again. The mesh is rendered by a child
+/// entity of the fluid, with a [`Mesh2d`] (in 2D) or [`Mesh3d`] (in 3D), so that the fluid entity
+/// can still render its own mesh, e.g. the one of its fluid surface.
+///
+/// Particle meshes are only built by the [`SalvaParticleMeshPlugin`].
+#[derive(Component, Reflect, Clone, Debug, PartialEq)]
+#[reflect(Component)]
+#[require(FluidParticleInstances, Transform)]
+pub struct FluidParticleMesh {
+    /// How the particles are colored.
+    pub color_mode: FluidDebugColorMode,
+    /// The scale of each particle shape, relative to the particle radius.
+    pub scale: Real,
+    /// The material of the mesh, which should use vertex colors.
+    ///
+    /// If `None`, a white material is used, which shows the vertex colors as they are.
+    pub material: Option<Handle<FluidParticleMaterial>>,
+}
+
+impl Default for FluidParticleMesh {
+    fn default() -> Self {
+        Self {
+            color_mode: FluidDebugColorMode::Fluid,
+            scale: 1.0,
+            material: None,
+        }
+    }
+}
+
+/// One particle of a [`FluidParticleMesh`].
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ParticleInstance {
+    pub position: Vect,
+    pub radius: Real,
+    pub color: LinearRgba,
+}
+
+/// The per-particle data of a [`FluidParticleMesh`], built from the fluid components, and the
+/// merged mesh built from it.
+#[derive(Component, Clone, Debug, Default)]
+pub struct FluidParticleInstances {
+    pub instances: Vec<ParticleInstance>,
+    pub mesh: Option<Handle<Mesh>>,
+    /// The child entity rendering [`Self::mesh`].
+    pub mesh_entity: Option<Entity>,
+}
+
+/// The shape copied for each particle of a [`FluidParticleMesh`], with a unit radius.
+#[derive(Clone, Debug, Default)]
+pub struct ParticleTemplate {
+    pub positions: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub indices: Vec<u32>,
+}
+
+impl ParticleTemplate {
+    /// Extracts the template from a triangle mesh with positions, normals and indices.
+    pub fn from_mesh(mesh: &Mesh) -> Self {
+        let float3 = |attribute| match mesh.attribute(attribute) {
+            Some(VertexAttributeValues::Float32x3(values)) => {
+                values.iter().copied().map(Vec3::from).collect()
+            }
+            _ => Vec::new(),
+        };
+        Self {
+            positions: float3(Mesh::ATTRIBUTE_POSITION),
+            normals: float3(Mesh::ATTRIBUTE_NORMAL),
+            indices: mesh
+                .indices()
+                .map(|indices| indices.iter().map(|i| i as u32).collect())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// A disk facing the camera.
+    #[cfg(feature = "dim2")]
+    pub fn default_shape() -> Self {
+        Self::from_mesh(&Circle::new(1.0).mesh().resolution(8).build())
+    }
+
+    /// A low-poly sphere.
+    #[cfg(feature = "dim3")]
+    pub fn default_shape() -> Self {
+        Self::from_mesh(&Sphere::new(1.0).mesh().ico(1).unwrap())
+    }
+}
+
+/// Builds the per-particle data of a fluid from its particles.
+///
+/// `densities` is only read with [`FluidDebugColorMode::Density`]. Particles without a density
+/// are colored as if at the minimum density.
+pub fn build_particle_instances(
+    positions: &[Vect],
+    velocities: &[Vect],
+    densities: Option<&[Real]>,
+    radius: Real,
+    fluid_color: Color,
+    color_mode: FluidDebugColorMode,
+) -> Vec<ParticleInstance> {
+    positions
+        .iter()
+        .enumerate()
+        .map(|(i, position)| {
+            let color = match color_mode {
+                FluidDebugColorMode::Fluid => fluid_color,
+                FluidDebugColorMode::Velocity { max_speed } => gradient_color_in(
+                    velocities.get(i).map_or(0.0, |v| v.length()),
+                    0.0,
+                    max_speed,
+                ),
+                FluidDebugColorMode::Density { min, max } => gradient_color_in(
+                    densities.and_then(|d| d.get(i).copied()).unwrap_or(min),
+                    min,
+                    max,
+                ),
+            };
+            ParticleInstance {
+                position: *position,
+                radius,
+                color: color.to_linear(),
+            }
+        })
+        .collect()
+}
+
+/// Builds a single mesh with one copy of `template` per instance, colored with vertex colors.
+///
+/// The instances are in world space, and the mesh in the space given by `world_to_mesh`, e.g.
+/// the inverse of the [`GlobalTransform`] of the entity rendering it.
+pub fn particle_instances_mesh(
+    instances: &[ParticleInstance],
+    template: &ParticleTemplate,
+    world_to_mesh: Affine3A,
+) -> Mesh {
+    // Normals are transformed by the inverse transpose of the mesh transform.
+    let normal_matrix = world_to_mesh.matrix3.inverse().transpose();
+    let template_normals: Vec<[f32; 3]> = template
+        .normals
+        .iter()
+        .map(|n| (normal_matrix * *n).normalize_or_zero().to_array())
+        .collect();
+    let vertex_count = instances.len() * template.positions.len();
+    let mut positions = Vec::with_capacity(vertex_count);
+    let mut normals = Vec::with_capacity(vertex_count);
+    let mut colors = Vec::with_capacity(vertex_count);
+    let mut indices = Vec::with_capacity(instances.len() * template.indices.len());
+
+    for (i, instance) in instances.iter().enumerate() {
+        #[cfg(feature = "dim2")]
+        let center = instance.position.extend(0.0);
+        #[cfg(feature = "dim3")]
+        let center = instance.position;
+
+        let first_index = (i * template.positions.len()) as u32;
+        positions.extend(
+            template
+                .positions
+                .iter()
+                .map(|p| world_to_mesh.transform_point3(center + *p * instance.radius).to_array()),
+        );
+        normals.extend_from_slice(&template_normals);
+        colors.extend(
+            std::iter::repeat(instance.color.to_f32_array()).take(template.positions.len()),
+        );
+        indices.extend(template.indices.iter().map(|k| first_index + k));
+    }
+
+    Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::default())
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_COLOR, colors)
+        .with_inserted_indices(Indices::U32(indices))
+}
+
+/// The system that rebuilds the instance buffer and mesh of every [`FluidParticleMesh`].
+pub fn update_fluid_particle_meshes(
+    mut commands: Commands,
+    mut meshes: Option<ResMut<Assets<Mesh>>>,
+    mut materials: Option<ResMut<Assets<FluidParticleMaterial>>>,
+    mut default_material: Local<Option<Handle<FluidParticleMaterial>>>,
+    mut template: Local<Option<ParticleTemplate>>,
+    mut fluids: Query<(
+        Entity,
+        Ref<FluidParticleMesh>,
+        &mut FluidParticleInstances,
+        Ref<GlobalTransform>,
+        Ref<FluidPositions>,
+        &FluidVelocities,
+        Option<Ref<FluidDensities>>,
+        &SalvaFluidHandle,
+        &SalvaContextEntityLink,
+        Option<&FluidDebugColor>,
+    )>,
+    salva_contexts: Query<&SalvaContext>,
+) {
+    let template = template.get_or_insert_with(ParticleTemplate::default_shape);
+
+    for (
+        entity,
+        particle_mesh,
+        mut particle_instances,
+        global_transform,
+        positions,
+        velocities,
+        densities,
+        handle,
+        link,
+        debug_color,
+    ) in fluids.iter_mut()
+    {
+        let uses_densities = matches!(particle_mesh.color_mode, FluidDebugColorMode::Density { .. });
+        if uses_densities && densities.is_none() {
+            commands.entity(entity).insert(FluidDensities::default());
+        }
+        let densities_changed = uses_densities && densities.as_ref().is_some_and(Ref::is_changed);
+        if !positions.is_changed()
+            && !particle_mesh.is_changed()
+            && !global_transform.is_changed()
+            && !densities_changed
+        {
+            continue;
+        }
+        let Ok(context) = salva_contexts.get(link.0) else {
+            continue;
+        };
+        let fluid_index = context
+            .liquid_world
+            .fluids()
+            .iter()
+            .position(|(h, _)| h == handle.0)
+            .unwrap_or_default();
+
+        particle_instances.instances = build_particle_instances(
+            &positions,
+            velocities,
+            densities.as_ref().map(|densities| densities.as_slice()),
+            context.liquid_world.particle_radius() * particle_mesh.scale,
+            debug_color.map_or_else(|| fluid_color(fluid_index), |color| color.0),
+            particle_mesh.color_mode,
+        );
+
+        let (Some(meshes), Some(materials)) = (meshes.as_mut(), materials.as_mut()) else {
+            continue;
+        };
+        // The mesh entity is a child of the fluid: build the mesh in the fluid's local space.
+        let mesh = particle_instances_mesh(
+            &particle_instances.instances,
+            template,
+            global_transform.affine().inverse(),
+        );
+        let material = particle_mesh.material.clone().unwrap_or_else(|| {
+            default_material
+                .get_or_insert_with(|| materials.add(FluidParticleMaterial::from(Color::WHITE)))
+                .clone()
+        });
+        let mesh_entity = particle_instances.mesh_entity;
+        match particle_instances
+            .mesh
+            .as_ref()
+            .and_then(|handle| meshes.get_mut(handle))
+            .zip(mesh_entity)
+        {
+            Some((existing, mesh_entity)) => {
+                *existing = mesh;
+                if particle_mesh.is_changed() {
+                    #[cfg(feature = "dim2")]
+                    commands.entity(mesh_entity).insert(MeshMaterial2d(material));
+                    #[cfg(feature = "dim3")]
+                    commands.entity(mesh_entity).insert(MeshMaterial3d(material));
+                }
+            }
+            None => {
+                if let Some(stale) = mesh_entity {
+                    if let Ok(mut stale) = commands.get_entity(stale) {
+                        stale.despawn();
+                    }
+                }
+                let handle = meshes.add(mesh);
+                #[cfg(feature = "dim2")]
+                let mesh_entity = commands
+                    .spawn((Mesh2d(handle.clone()), MeshMaterial2d(material), ChildOf(entity)))
+                    .id();
+                #[cfg(feature = "dim3")]
+                let mesh_entity = commands
+                    .spawn((Mesh3d(handle.clone()), MeshMaterial3d(material), ChildOf(entity)))
+                    .id();
+                particle_instances.mesh = Some(handle);
+                particle_instances.mesh_entity = Some(mesh_entity);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::gradient_color;
+
+    fn colors(instances: &[ParticleInstance]) -> Vec<LinearRgba> {
+        instances.iter().map(|instance| instance.color).collect()
+    }
+
+    #[test]
+    fn instances_follow_the_particles() {
+        let positions = [Vect::ZERO, Vect::ONE];
+        let instances = build_particle_instances(
+            &positions,
+            &[],
+            None,
+            0.5,
+            Color::WHITE,
+            FluidDebugColorMode::Fluid,
+        );
+        assert_eq!(instances.len(), 2);
+        for (instance, position) in instances.iter().zip(positions) {
+            assert_eq!(instance.position, position);
+            assert_eq!(instance.radius, 0.5);
+            assert_eq!(instance.color, LinearRgba::WHITE);
+        }
+    }
+
+    #[test]
+    fn velocity_and_density_colors_follow_the_gradient() {
+        let positions = [Vect::ZERO; 2];
+        let velocities = [Vect::ZERO, Vect::X * 10.0];
+        let instances = build_particle_instances(
+            &positions,
+            &velocities,
+            None,
+            1.0,
+            Color::WHITE,
+            FluidDebugColorMode::Velocity { max_speed: 5.0 },
+        );
+        let expected = [gradient_color(0.0).to_linear(), gradient_color(1.0).to_linear()];
+        assert_eq!(colors(&instances), expected);
+
+        let instances = build_particle_instances(
+            &positions,
+            &[],
+            Some(&[900.0, 1100.0]),
+            1.0,
+            Color::WHITE,
+            FluidDebugColorMode::Density { min: 1000.0, max: 1050.0 },
+        );
+        assert_eq!(colors(&instances), expected);
+    }
+
+    #[test]
+    fn empty_color_ranges_give_finite_colors() {
+        let positions = [Vect::ZERO; 2];
+        let modes = [
+            FluidDebugColorMode::Velocity { max_speed: 0.0 },
+            FluidDebugColorMode::Density { min: 1000.0, max: 1000.0 },
+        ];
+        for color_mode in modes {
+            let instances = build_particle_instances(
+                &positions,
+                &[Vect::ZERO, Vect::X],
+                Some(&[1000.0, 1000.0]),
+                1.0,
+                Color::WHITE,
+                color_mode,
+            );
+            assert!(colors(&instances)
+                .iter()
+                .all(|color| color.to_f32_array().iter().all(|c| c.is_finite())));
+        }
+    }
+
+    #[test]
+    fn meshes_are_built_in_the_given_space() {
+        let template = ParticleTemplate {
+            positions: vec![Vec3::X],
+            normals: vec![Vec3::X],
+            indices: vec![0, 0, 0],
+        };
+        let instance = ParticleInstance {
+            position: Vect::ONE,
+            radius: 2.0,
+            color: LinearRgba::WHITE,
+        };
+        let world_to_mesh = Affine3A::from_translation(-Vec3::ONE) * Affine3A::from_scale(Vec3::splat(0.5));
+        let mesh = particle_instances_mesh(&[instance, instance], &template, world_to_mesh);
+
+        #[cfg(feature = "dim2")]
+        let center = instance.position.extend(0.0);
+        #[cfg(feature = "dim3")]
+        let center = instance.position;
+        let expected = world_to_mesh.transform_point3(center + Vec3::X * 2.0).to_array();
+        let Some(VertexAttributeValues::Float32x3(positions)) = mesh.attribute(Mesh::ATTRIBUTE_POSITION) else {
+            panic!("particle meshes have positions");
+        };
+        assert_eq!(positions, &[expected, expected]);
+        let Some(VertexAttributeValues::Float32x3(normals)) = mesh.attribute(Mesh::ATTRIBUTE_NORMAL) else {
+            panic!("particle meshes have normals");
+        };
+        assert_eq!(normals, &[[1.0, 0.0, 0.0]; 2]);
+        assert_eq!(mesh.indices().unwrap().iter().collect::<Vec<_>>(), [0, 0, 0, 1, 1, 1]);
+    }
+
+    #[test]
+    fn missing_densities_use_the_minimum() {
+        let instances = build_particle_instances(
+            &[Vect::ZERO],
+            &[],
+            None,
+            1.0,
+            Color::WHITE,
+            FluidDebugColorMode::Density { min: 1000.0, max: 1050.0 },
+        );
+        assert_eq!(colors(&instances), [gradient_color(0.0).to_linear()]);
+    }
+}
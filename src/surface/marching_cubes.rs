@@ -0,0 +1,301 @@
+use bevy::math::{IVec3, Vec3};
+use std::collections::{HashMap, HashSet};
+
+/// A scalar field sampled at the vertices of a regular 3D grid, stored sparsely.
+///
+/// Vertex `v` is at position `v * cell_size`. Vertices that aren't stored have the value 0.0.
+#[derive(Clone, Debug, Default)]
+pub struct SparseScalarGrid3d {
+    /// The distance between two adjacent vertices.
+    pub cell_size: f32,
+    /// The values of the stored vertices.
+    pub values: HashMap<IVec3, f32>,
+}
+
+impl SparseScalarGrid3d {
+    pub fn new(cell_size: f32) -> Self {
+        Self {
+            cell_size,
+            values: HashMap::new(),
+        }
+    }
+
+    pub fn value(&self, vertex: IVec3) -> f32 {
+        self.values.get(&vertex).copied().unwrap_or(0.0)
+    }
+
+    /// The position of the given vertex.
+    pub fn vertex(&self, vertex: IVec3) -> Vec3 {
+        vertex.as_vec3() * self.cell_size
+    }
+}
+
+/// The triangulated surface of the region where a [`SparseScalarGrid3d`] is above an iso-level.
+#[derive(Clone, Debug, Default)]
+pub struct MarchingCubesOutput {
+    /// The vertices of the surface, shared by adjacent triangles.
+    pub vertices: Vec<Vec3>,
+    /// Counterclockwise triangles indexing [`Self::vertices`], facing away from the region.
+    pub indices: Vec<u32>,
+}
+
+impl MarchingCubesOutput {
+    /// Moves each vertex halfway towards the average of its neighbors, `iterations` times.
+    pub fn smooth(&mut self, iterations: usize) {
+        if iterations == 0 {
+            return;
+        }
+
+        let mut neighbors: Vec<HashSet<u32>> = vec![HashSet::new(); self.vertices.len()];
+        for triangle in self.indices.chunks_exact(3) {
+            for k in 0..3 {
+                let (a, b) = (triangle[k], triangle[(k + 1) % 3]);
+                neighbors[a as usize].insert(b);
+                neighbors[b as usize].insert(a);
+            }
+        }
+
+        for _ in 0..iterations {
+            self.vertices = self
+                .vertices
+                .iter()
+                .zip(neighbors.iter())
+                .map(|(vertex, neighbors)| {
+                    if neighbors.is_empty() {
+                        return *vertex;
+                    }
+                    let average = neighbors
+                        .iter()
+                        .map(|j| self.vertices[*j as usize])
+                        .sum::<Vec3>()
+                        / neighbors.len() as f32;
+                    vertex.lerp(average, 0.5)
+                })
+                .collect();
+        }
+    }
+
+    /// Area-weighted vertex normals.
+    pub fn normals(&self) -> Vec<Vec3> {
+        let mut normals = vec![Vec3::ZERO; self.vertices.len()];
+        for triangle in self.indices.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|k| self.vertices[triangle[k] as usize]);
+            let normal = (b - a).cross(c - a);
+            for k in triangle {
+                normals[*k as usize] += normal;
+            }
+        }
+        normals.iter().map(|n| n.normalize_or(Vec3::Y)).collect()
+    }
+}
+
+const CORNERS: [IVec3; 8] = [
+    IVec3::new(0, 0, 0),
+    IVec3::new(1, 0, 0),
+    IVec3::new(1, 1, 0),
+    IVec3::new(0, 1, 0),
+    IVec3::new(0, 0, 1),
+    IVec3::new(1, 0, 1),
+    IVec3::new(1, 1, 1),
+    IVec3::new(0, 1, 1),
+];
+
+/// The corners of each face of a cell, counterclockwise when seen from outside the cell.
+const FACES: [[usize; 4]; 6] = [
+    [0, 3, 2, 1],
+    [4, 5, 6, 7],
+    [0, 1, 5, 4],
+    [3, 7, 6, 2],
+    [0, 4, 7, 3],
+    [1, 2, 6, 5],
+];
+
+/// A grid edge, identified by its lowest vertex and its axis.
+type EdgeKey = (IVec3, u8);
+
+fn edge_key(a: IVec3, b: IVec3) -> EdgeKey {
+    let axis = if a.x != b.x {
+        0
+    } else if a.y != b.y {
+        1
+    } else {
+        2
+    };
+    (a.min(b), axis)
+}
+
+/// Extracts the surface of the region where `grid` is greater or equal to `iso_level`.
+///
+/// This is a table-free variant of marching cubes: the iso-lines of each face of a cell are
+/// found with marching squares, then chained into the polygons triangulated inside the cell.
+/// Ambiguous faces are connected if the average of their corners is above the iso-level, which
+/// keeps the surface consistent between adjacent cells.
+pub fn marching_cubes(grid: &SparseScalarGrid3d, iso_level: f32) -> MarchingCubesOutput {
+    let mut output = MarchingCubesOutput::default();
+    let mut vertex_ids: HashMap<EdgeKey, u32> = HashMap::new();
+
+    // Only the cells touching a stored vertex can contain a piece of the surface.
+    let mut cells: HashSet<IVec3> = HashSet::new();
+    for vertex in grid.values.keys() {
+        for offset in CORNERS {
+            cells.insert(*vertex - offset);
+        }
+    }
+
+    for cell in cells {
+        let corners = CORNERS.map(|offset| cell + offset);
+        let values = corners.map(|corner| grid.value(corner));
+        let inside = values.map(|v| v >= iso_level);
+        if inside.iter().all(|i| *i) || inside.iter().all(|i| !*i) {
+            continue;
+        }
+
+        let mut vertex_id = |a: usize, b: usize| {
+            *vertex_ids
+                .entry(edge_key(corners[a], corners[b]))
+                .or_insert_with(|| {
+                    let t = ((iso_level - values[a]) / (values[b] - values[a])).clamp(0.0, 1.0);
+                    output
+                        .vertices
+                        .push(grid.vertex(corners[a]).lerp(grid.vertex(corners[b]), t));
+                    output.vertices.len() as u32 - 1
+                })
+        };
+
+        // Iso-line segments on each face, with the inside of the region on their left when
+        // seen from outside the cell.
+        let mut segments: HashMap<u32, u32> = HashMap::new();
+        for face in FACES {
+            let face_inside = face.map(|k| inside[k]);
+            let crossings: Vec<usize> = (0..4)
+                .filter(|i| face_inside[*i] != face_inside[(*i + 1) % 4])
+                .collect();
+            let crossing_id = |i: usize| (face[i], face[(i + 1) % 4]);
+
+            match crossings.len() {
+                2 => {
+                    // The edge leaving the inside region comes first when walking the face.
+                    let (first, second) = if face_inside[crossings[0]] {
+                        (crossings[0], crossings[1])
+                    } else {
+                        (crossings[1], crossings[0])
+                    };
+                    let (a, b) = crossing_id(first);
+                    let start = vertex_id(a, b);
+                    let (a, b) = crossing_id(second);
+                    let end = vertex_id(a, b);
+                    segments.insert(start, end);
+                }
+                4 => {
+                    let center = face.iter().map(|k| values[*k]).sum::<f32>() / 4.0;
+                    for i in (0..4).filter(|i| face_inside[*i]) {
+                        // Connected: the inside corner `i` links to the next inside corner.
+                        // Separated: the segment goes around the inside corner `i`.
+                        let (start, end) = if center >= iso_level {
+                            (i, (i + 1) % 4)
+                        } else {
+                            (i, (i + 3) % 4)
+                        };
+                        let (a, b) = crossing_id(start);
+                        let start = vertex_id(a, b);
+                        let (a, b) = crossing_id(end);
+                        let end = vertex_id(a, b);
+                        segments.insert(start, end);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // Chain the segments into polygons and triangulate them, facing away from the region.
+        while let Some(&start) = segments.keys().next() {
+            let mut polygon = vec![start];
+            let mut current = start;
+            while let Some(next) = segments.remove(&current) {
+                if next == start {
+                    break;
+                }
+                polygon.push(next);
+                current = next;
+            }
+            for i in 1..polygon.len().saturating_sub(1) {
+                output
+                    .indices
+                    .extend([polygon[0], polygon[i + 1], polygon[i]]);
+            }
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid(inside: impl IntoIterator<Item = IVec3>) -> SparseScalarGrid3d {
+        let mut grid = SparseScalarGrid3d::new(1.0);
+        grid.values.extend(inside.into_iter().map(|vertex| (vertex, 1.0)));
+        grid
+    }
+
+    /// Checks that every edge is shared by exactly two triangles going through it in opposite
+    /// directions, i.e. the surface is closed and consistently oriented.
+    fn assert_closed(output: &MarchingCubesOutput) {
+        let mut edges: HashMap<(u32, u32), usize> = HashMap::new();
+        for triangle in output.indices.chunks_exact(3) {
+            for k in 0..3 {
+                *edges.entry((triangle[k], triangle[(k + 1) % 3])).or_default() += 1;
+            }
+        }
+        for ((a, b), count) in edges.iter() {
+            assert_eq!(*count, 1, "edge {a}-{b} is used twice in the same direction");
+            assert_eq!(edges.get(&(*b, *a)), Some(&1), "edge {a}-{b} is on the border");
+        }
+    }
+
+    /// The volume enclosed by the surface, positive if its triangles face outwards.
+    fn signed_volume(output: &MarchingCubesOutput) -> f32 {
+        output
+            .indices
+            .chunks_exact(3)
+            .map(|triangle| {
+                let [a, b, c] = [0, 1, 2].map(|k| output.vertices[triangle[k] as usize]);
+                a.dot(b.cross(c)) / 6.0
+            })
+            .sum()
+    }
+
+    #[test]
+    fn empty_fields_have_no_surface() {
+        let output = marching_cubes(&SparseScalarGrid3d::new(1.0), 0.5);
+        assert!(output.vertices.is_empty());
+        assert!(output.indices.is_empty());
+    }
+
+    #[test]
+    fn single_vertices_are_closed_octahedra() {
+        let output = marching_cubes(&grid([IVec3::ZERO]), 0.5);
+        assert_eq!(output.vertices.len(), 6);
+        assert_eq!(output.indices.len(), 8 * 3);
+        assert_closed(&output);
+        // An octahedron with vertices at distance 0.5 from its center.
+        assert!((signed_volume(&output) - 4.0 / 3.0 * 0.125).abs() < 1.0e-5);
+    }
+
+    #[test]
+    fn blocks_are_closed_and_face_outwards() {
+        let block = (0..27).map(|i| IVec3::new(i % 3, i / 3 % 3, i / 9));
+        let output = marching_cubes(&grid(block), 0.5);
+        assert_closed(&output);
+        assert!(signed_volume(&output) > 8.0);
+    }
+
+    #[test]
+    fn diagonal_vertices_stay_consistent_between_cells() {
+        // Ambiguous faces between the two vertices must be resolved the same way by both cells.
+        let output = marching_cubes(&grid([IVec3::ZERO, IVec3::new(1, 1, 0), IVec3::ONE]), 0.5);
+        assert_closed(&output);
+        assert!(signed_volume(&output) > 0.0);
+    }
+}
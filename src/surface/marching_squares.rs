@@ -0,0 +1,271 @@
+use bevy::math::{UVec2, Vec2};
+use std::collections::HashMap;
+
+/// A scalar field sampled at the vertices of a regular 2D grid.
+#[derive(Clone, Debug, Default)]
+pub struct ScalarGrid2d {
+    /// The position of the vertex `(0, 0)`.
+    pub origin: Vec2,
+    /// The distance between two adjacent vertices.
+    pub cell_size: f32,
+    /// The number of vertices along each axis.
+    pub dims: UVec2,
+    /// The field values, indexed by `y * dims.x + x`.
+    pub values: Vec<f32>,
+}
+
+impl ScalarGrid2d {
+    /// Creates a grid of the given dimensions with all values set to zero.
+    pub fn new(origin: Vec2, cell_size: f32, dims: UVec2) -> Self {
+        Self {
+            origin,
+            cell_size,
+            dims,
+            values: vec![0.0; (dims.x * dims.y) as usize],
+        }
+    }
+
+    pub fn index(&self, x: u32, y: u32) -> usize {
+        (y * self.dims.x + x) as usize
+    }
+
+    pub fn value(&self, x: u32, y: u32) -> f32 {
+        self.values[self.index(x, y)]
+    }
+
+    /// The position of the vertex `(x, y)`.
+    pub fn vertex(&self, x: u32, y: u32) -> Vec2 {
+        self.origin + Vec2::new(x as f32, y as f32) * self.cell_size
+    }
+}
+
+/// The surface of the region where a [`ScalarGrid2d`] is above an iso-level.
+#[derive(Clone, Debug, Default)]
+pub struct MarchingSquaresOutput {
+    /// The vertices of the triangulated region.
+    pub vertices: Vec<Vec2>,
+    /// Counterclockwise triangles indexing [`Self::vertices`].
+    pub indices: Vec<u32>,
+    /// The outlines of the region. Each contour is a polyline going counterclockwise around the
+    /// region (clockwise around holes). Closed contours end with their first point.
+    pub contours: Vec<Vec<Vec2>>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+enum VertexKey {
+    Corner(u32, u32),
+    /// A point on the grid edge going from `(x, y)` along the X axis (`false`) or Y axis (`true`).
+    Edge(u32, u32, bool),
+}
+
+/// Extracts the region where `grid` is greater or equal to `iso_level` with marching squares.
+///
+/// Ambiguous (saddle) cells are connected if the average of their corners is above the iso-level.
+pub fn marching_squares(grid: &ScalarGrid2d, iso_level: f32) -> MarchingSquaresOutput {
+    let mut output = MarchingSquaresOutput::default();
+    let mut vertex_ids: HashMap<VertexKey, u32> = HashMap::new();
+    let mut edge_points: HashMap<VertexKey, Vec2> = HashMap::new();
+    let mut segments: HashMap<VertexKey, VertexKey> = HashMap::new();
+
+    if grid.dims.x < 2 || grid.dims.y < 2 {
+        return output;
+    }
+
+    for y in 0..grid.dims.y - 1 {
+        for x in 0..grid.dims.x - 1 {
+            // Counterclockwise corners, and the edges going from each corner to the next one.
+            let corners = [(x, y), (x + 1, y), (x + 1, y + 1), (x, y + 1)];
+            let edges = [
+                VertexKey::Edge(x, y, false),
+                VertexKey::Edge(x + 1, y, true),
+                VertexKey::Edge(x, y + 1, false),
+                VertexKey::Edge(x, y, true),
+            ];
+            let values = corners.map(|(cx, cy)| grid.value(cx, cy));
+            let inside = values.map(|v| v >= iso_level);
+
+            if inside.iter().all(|i| !*i) {
+                continue;
+            }
+
+            let edge_point = |k: usize| {
+                let (a, b) = (corners[k], corners[(k + 1) % 4]);
+                let (va, vb) = (values[k], values[(k + 1) % 4]);
+                let t = ((iso_level - va) / (vb - va)).clamp(0.0, 1.0);
+                grid.vertex(a.0, a.1).lerp(grid.vertex(b.0, b.1), t)
+            };
+
+            let mut polygons: Vec<Vec<VertexKey>> = Vec::new();
+            let is_saddle = inside == [true, false, true, false] || inside == [false, true, false, true];
+            let center = values.iter().sum::<f32>() / 4.0;
+
+            if is_saddle && center < iso_level {
+                // Two separate triangles around the inside corners.
+                for k in (0..4).filter(|k| inside[*k]) {
+                    let (cx, cy) = corners[k];
+                    polygons.push(vec![
+                        edges[(k + 3) % 4],
+                        VertexKey::Corner(cx, cy),
+                        edges[k],
+                    ]);
+                }
+            } else {
+                let mut polygon = Vec::new();
+                for k in 0..4 {
+                    if inside[k] {
+                        let (cx, cy) = corners[k];
+                        polygon.push(VertexKey::Corner(cx, cy));
+                    }
+                    if inside[k] != inside[(k + 1) % 4] {
+                        polygon.push(edges[k]);
+                    }
+                }
+                polygons.push(polygon);
+            }
+
+            for k in 0..4 {
+                if inside[k] != inside[(k + 1) % 4] {
+                    edge_points.entry(edges[k]).or_insert_with(|| edge_point(k));
+                }
+            }
+
+            for polygon in polygons {
+                let ids: Vec<u32> = polygon
+                    .iter()
+                    .map(|key| {
+                        *vertex_ids.entry(*key).or_insert_with(|| {
+                            output.vertices.push(match key {
+                                VertexKey::Corner(cx, cy) => grid.vertex(*cx, *cy),
+                                VertexKey::Edge(..) => edge_points[key],
+                            });
+                            output.vertices.len() as u32 - 1
+                        })
+                    })
+                    .collect();
+                for i in 1..ids.len() - 1 {
+                    output.indices.extend([ids[0], ids[i], ids[i + 1]]);
+                }
+
+                // Two consecutive edge points along the polygon form a piece of the outline.
+                for i in 0..polygon.len() {
+                    let (a, b) = (polygon[i], polygon[(i + 1) % polygon.len()]);
+                    if matches!(a, VertexKey::Edge(..)) && matches!(b, VertexKey::Edge(..)) {
+                        segments.insert(a, b);
+                    }
+                }
+            }
+        }
+    }
+
+    // Chain the segments into contours, starting with the open ones.
+    let ends: std::collections::HashSet<_> = segments.values().copied().collect();
+    let mut starts: Vec<_> = segments.keys().filter(|k| !ends.contains(k)).copied().collect();
+    starts.extend(segments.keys().copied());
+
+    for start in starts {
+        if !segments.contains_key(&start) {
+            continue;
+        }
+        let mut contour = vec![edge_points[&start]];
+        let mut current = start;
+        while let Some(next) = segments.remove(&current) {
+            contour.push(edge_points[&next]);
+            current = next;
+        }
+        output.contours.push(contour);
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A grid with the value 1.0 at the vertices for which `inside` is true.
+    fn grid(dims: UVec2, inside: impl Fn(u32, u32) -> bool) -> ScalarGrid2d {
+        let mut grid = ScalarGrid2d::new(Vec2::ZERO, 1.0, dims);
+        for y in 0..dims.y {
+            for x in 0..dims.x {
+                let index = grid.index(x, y);
+                grid.values[index] = if inside(x, y) { 1.0 } else { 0.0 };
+            }
+        }
+        grid
+    }
+
+    fn signed_area(points: &[Vec2]) -> f32 {
+        points
+            .iter()
+            .zip(points.iter().cycle().skip(1))
+            .map(|(a, b)| a.perp_dot(*b))
+            .sum::<f32>()
+            / 2.0
+    }
+
+    fn assert_closed(contour: &[Vec2]) {
+        assert!(contour.len() > 3);
+        assert_eq!(contour.first(), contour.last());
+    }
+
+    #[test]
+    fn empty_fields_have_no_surface() {
+        let output = marching_squares(&grid(UVec2::splat(4), |_, _| false), 0.5);
+        assert!(output.vertices.is_empty());
+        assert!(output.indices.is_empty());
+        assert!(output.contours.is_empty());
+    }
+
+    #[test]
+    fn blobs_are_closed_and_counterclockwise() {
+        let block = |x: u32, y: u32| (1..=3).contains(&x) && (1..=3).contains(&y);
+        let output = marching_squares(&grid(UVec2::splat(5), block), 0.5);
+
+        // The iso-line crosses the edges halfway: a 3x3 square with its corners cut.
+        let expected_area = 9.0 - 4.0 * 0.125;
+        assert_eq!(output.contours.len(), 1);
+        let contour = &output.contours[0];
+        assert_closed(contour);
+        assert!((signed_area(&contour[..contour.len() - 1]) - expected_area).abs() < 1.0e-4);
+
+        let mut area = 0.0;
+        for triangle in output.indices.chunks_exact(3) {
+            let triangle: Vec<Vec2> = triangle.iter().map(|i| output.vertices[*i as usize]).collect();
+            let triangle_area = signed_area(&triangle);
+            assert!(triangle_area > 0.0);
+            area += triangle_area;
+        }
+        assert!((area - expected_area).abs() < 1.0e-4);
+    }
+
+    #[test]
+    fn holes_are_clockwise() {
+        let ring = |x: u32, y: u32| {
+            (1..=5).contains(&x) && (1..=5).contains(&y) && (x, y) != (3, 3)
+        };
+        let output = marching_squares(&grid(UVec2::splat(7), ring), 0.5);
+
+        assert_eq!(output.contours.len(), 2);
+        let mut areas: Vec<f32> = output
+            .contours
+            .iter()
+            .map(|contour| {
+                assert_closed(contour);
+                signed_area(&contour[..contour.len() - 1])
+            })
+            .collect();
+        areas.sort_by(f32::total_cmp);
+        assert!(areas[0] < 0.0, "the hole should be clockwise");
+        assert!(areas[1] > 0.0, "the outline should be counterclockwise");
+    }
+
+    #[test]
+    fn contours_touching_the_grid_border_are_open() {
+        let output = marching_squares(&grid(UVec2::new(4, 3), |x, _| x < 2), 0.5);
+        assert_eq!(output.contours.len(), 1);
+        let contour = &output.contours[0];
+        assert_ne!(contour.first(), contour.last());
+        // The region is on the left of the outline.
+        assert!(contour.first().unwrap().y < contour.last().unwrap().y);
+    }
+}
@@ -0,0 +1,41 @@
+//! Surface reconstruction turning fluid particles into solid shapes for rendering.
+
+use crate::plugin::SalvaSimulationSet;
+use bevy::prelude::*;
+
+#[cfg(feature = "dim2")]
+pub use self::surface2d::*;
+#[cfg(feature = "dim3")]
+pub use self::surface3d::*;
+
+#[cfg(feature = "dim3")]
+pub mod marching_cubes;
+#[cfg(feature = "dim2")]
+pub mod marching_squares;
+#[cfg(feature = "dim2")]
+mod surface2d;
+#[cfg(feature = "dim3")]
+mod surface3d;
+
+/// Plugin that rebuilds the surfaces of the fluids with a `FluidSurface2d` (in 2D) or a
+/// `FluidSurface3d` (in 3D) after the simulation steps.
+pub struct SalvaSurfacePlugin;
+
+impl Plugin for SalvaSurfacePlugin {
+    fn build(&self, app: &mut App) {
+        #[cfg(feature = "dim2")]
+        app.register_type::<FluidSurface2d>().add_systems(
+            PostUpdate,
+            update_fluid_surfaces_2d
+                .after(SalvaSimulationSet::Writeback)
+                .after(TransformSystem::TransformPropagate),
+        );
+        #[cfg(feature = "dim3")]
+        app.register_type::<FluidSurface3d>().add_systems(
+            PostUpdate,
+            update_fluid_surfaces_3d
+                .after(SalvaSimulationSet::Writeback)
+                .after(TransformSystem::TransformPropagate),
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use super::marching_squares::{marching_squares, ScalarGrid2d};
+use crate::fluid::FluidPositions;
+use crate::math::{Real, Vect};
+use crate::plugin::{SalvaContext, SalvaContextEntityLink};
+use crate::utils;
+use bevy::asset::RenderAssetUsages;
+use bevy::prelude::*;
+use bevy::render::mesh::{Indices, PrimitiveTopology};
+use salva::kernel::{CubicSplineKernel, Kernel};
+
+/// Add this to a fluid entity to build the outline of the fluid from its particles, with a
+/// density field and marching squares. The result is written to its [`FluidSurfaceOutline`].
+///
+/// Surfaces are only built by the [`SalvaSurfacePlugin`](super::SalvaSurfacePlugin).
+#[derive(Component, Reflect, Clone, Debug, PartialEq)]
+#[reflect(Component)]
+#[require(FluidSurfaceOutline, Transform)]
+pub struct FluidSurface2d {
+    /// The distance between two samples of the density field.
+    ///
+    /// If `None`, the particle radius of the fluid's [`SalvaContext`] is used.
+    pub cell_size: Option<Real>,
+    /// The radius of the SPH kernel used to compute the density field.
+    ///
+    /// If `None`, the kernel radius of the fluid's [`SalvaContext`] is used.
+    pub kernel_radius: Option<Real>,
+    /// The value of the normalized density field (1.0 inside the fluid, 0.0 outside) at the
+    /// surface of the fluid.
+    pub iso_level: Real,
+    /// Should a [`Mesh`] of the fluid be built? It is added to the entity as a [`Mesh2d`].
+    pub build_mesh: bool,
+}
+
+impl Default for FluidSurface2d {
+    fn default() -> Self {
+        Self {
+            cell_size: None,
+            kernel_radius: None,
+            iso_level: 0.5,
+            build_mesh: true,
+        }
+    }
+}
+
+/// The outline of a fluid built from its [`FluidSurface2d`].
+#[derive(Component, Clone, Debug, Default)]
+pub struct FluidSurfaceOutline {
+    /// The outlines of the fluid in world space, going counterclockwise around it (clockwise
+    /// around holes). Closed contours end with their first point.
+    pub contours: Vec<Vec<Vect>>,
+    /// The mesh of the fluid, in the local space of the fluid entity, if
+    /// [`FluidSurface2d::build_mesh`] is set.
+    pub mesh: Option<Handle<Mesh>>,
+}
+
+/// The largest number of vertices of the grid sampled by [`density_field_2d`].
+pub const MAX_DENSITY_FIELD_VERTICES: usize = 1 << 22;
+
+/// Samples the normalized density field of the given particles on a grid covering them.
+///
+/// The field is `sum_j V_j W(|x - x_j|, h)`, which is close to 1.0 inside the fluid.
+///
+/// The grid is dense, so particles far apart from each other cover a lot of vertices. If the grid
+/// would have more than [`MAX_DENSITY_FIELD_VERTICES`], its cell size is doubled until it fits,
+/// and a warning is logged.
+pub fn density_field_2d(
+    positions: &[Vect],
+    particle_volume: Real,
+    kernel_radius: Real,
+    cell_size: Real,
+) -> ScalarGrid2d {
+    if positions.is_empty() {
+        return ScalarGrid2d::default();
+    }
+
+    let mut mins = Vect::splat(Real::MAX);
+    let mut maxs = Vect::splat(Real::MIN);
+    for pos in positions {
+        mins = mins.min(*pos);
+        maxs = maxs.max(*pos);
+    }
+
+    // Pad the grid so that the field vanishes on its border.
+    let padded_size =
+        |cell_size: Real| maxs - mins + Vect::splat(2.0 * (kernel_radius + cell_size));
+    let vertex_count = |cell_size: Real| {
+        let cells = (padded_size(cell_size) / cell_size).ceil();
+        (cells.x as f64 + 1.0) * (cells.y as f64 + 1.0)
+    };
+    let mut cell_size = cell_size;
+    if vertex_count(cell_size) > MAX_DENSITY_FIELD_VERTICES as f64 {
+        let requested = cell_size;
+        while vertex_count(cell_size) > MAX_DENSITY_FIELD_VERTICES as f64 {
+            cell_size *= 2.0;
+        }
+        warn_once!(
+            "The particles of a fluid surface are too far apart to sample its density field with \
+            cells of {requested}; cells of {cell_size} are used instead."
+        );
+    }
+
+    let origin = mins - Vect::splat(kernel_radius + cell_size);
+    let dims = (padded_size(cell_size) / cell_size).ceil().as_uvec2() + UVec2::ONE;
+    let mut field = ScalarGrid2d::new(origin, cell_size, dims);
+
+    // Add the contribution of each particle to the vertices within its kernel radius.
+    for pos in positions {
+        let first = ((*pos - Vect::splat(kernel_radius) - origin) / cell_size)
+            .floor()
+            .max(Vect::ZERO)
+            .as_uvec2();
+        let last = ((*pos + Vect::splat(kernel_radius) - origin) / cell_size)
+            .ceil()
+            .as_uvec2()
+            .min(dims - UVec2::ONE);
+        for y in first.y..=last.y {
+            for x in first.x..=last.x {
+                let weight = CubicSplineKernel::scalar_apply(
+                    field.vertex(x, y).distance(*pos),
+                    kernel_radius,
+                );
+                if weight > 0.0 {
+                    let i = field.index(x, y);
+                    field.values[i] += particle_volume * weight;
+                }
+            }
+        }
+    }
+
+    field
+}
+
+/// Builds a 2D triangle [`Mesh`] from triangulated vertices.
+///
+/// The vertices are in world space, and the mesh in the space given by `world_to_mesh`, e.g. the
+/// inverse of the [`GlobalTransform`] of the entity rendering it. The mesh stays in the XY plane.
+pub fn triangle_mesh_2d(vertices: &[Vect], indices: Vec<u32>, world_to_mesh: Affine3A) -> Mesh {
+    let vertices: Vec<Vect> = vertices
+        .iter()
+        .map(|v| world_to_mesh.transform_point3(v.extend(0.0)).truncate())
+        .collect();
+    let positions: Vec<[f32; 3]> = vertices.iter().map(|v| [v.x, v.y, 0.0]).collect();
+    let normals = vec![[0.0, 0.0, 1.0]; vertices.len()];
+    let uvs: Vec<[f32; 2]> = vertices.iter().map(|v| [v.x, v.y]).collect();
+
+    Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::default())
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
+        .with_inserted_indices(Indices::U32(indices))
+}
+
+/// The system that rebuilds the [`FluidSurfaceOutline`] of fluids whose particles or transform
+/// changed.
+pub fn update_fluid_surfaces_2d(
+    mut commands: Commands,
+    mut meshes: Option<ResMut<Assets<Mesh>>>,
+    mut fluids: Query<
+        (
+            Entity,
+            &FluidSurface2d,
+            &mut FluidSurfaceOutline,
+            &GlobalTransform,
+            &FluidPositions,
+            &SalvaContextEntityLink,
+        ),
+        Or<(
+            Changed<FluidPositions>,
+            Changed<FluidSurface2d>,
+            Changed<GlobalTransform>,
+        )>,
+    >,
+    salva_contexts: Query<&SalvaContext>,
+) {
+    for (entity, surface, mut outline, global_transform, positions, link) in fluids.iter_mut() {
+        let Ok(context) = salva_contexts.get(link.0) else {
+            continue;
+        };
+        let particle_radius = context.liquid_world.particle_radius();
+        let kernel_radius = surface.kernel_radius.unwrap_or(context.liquid_world.h());
+        let cell_size = surface.cell_size.unwrap_or(particle_radius);
+
+        let field = density_field_2d(
+            positions,
+            utils::particle_volume(particle_radius),
+            kernel_radius,
+            cell_size,
+        );
+        let output = marching_squares(&field, surface.iso_level);
+        outline.contours = output.contours;
+
+        if !surface.build_mesh {
+            continue;
+        }
+        let Some(meshes) = meshes.as_mut() else {
+            continue;
+        };
+        let mesh = triangle_mesh_2d(
+            &output.vertices,
+            output.indices,
+            global_transform.affine().inverse(),
+        );
+        match outline.mesh.as_ref().and_then(|handle| meshes.get_mut(handle)) {
+            Some(existing) => *existing = mesh,
+            None => {
+                let handle = meshes.add(mesh);
+                commands.entity(entity).insert(Mesh2d(handle.clone()));
+                outline.mesh = Some(handle);
+            }
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use super::marching_cubes::{marching_cubes, SparseScalarGrid3d};
+use crate::fluid::FluidPositions;
+use crate::math::{Real, Vect};
+use crate::plugin::{SalvaContext, SalvaContextEntityLink};
+use crate::utils;
+use bevy::asset::RenderAssetUsages;
+use bevy::prelude::*;
+use bevy::render::mesh::{Indices, PrimitiveTopology};
+use salva::kernel::{CubicSplineKernel, Kernel};
+
+/// Add this to a fluid entity to build a [`Mesh`] of the fluid's surface from its particles,
+/// with an SPH density field and marching cubes on a sparse grid.
+///
+/// The mesh is built in the local space of the entity and added to it as a [`Mesh3d`], and its
+/// handle is kept in the [`FluidSurfaceMesh`]. Surfaces are only built by the
+/// [`SalvaSurfacePlugin`](super::SalvaSurfacePlugin).
+#[derive(Component, Reflect, Clone, Debug, PartialEq)]
+#[reflect(Component)]
+#[require(FluidSurfaceMesh, Transform)]
+pub struct FluidSurface3d {
+    /// The distance between two samples of the density field.
+    ///
+    /// If `None`, the particle radius of the fluid's [`SalvaContext`] is used.
+    pub cell_size: Option<Real>,
+    /// The radius of the SPH kernel used to compute the density field.
+    ///
+    /// If `None`, the kernel radius of the fluid's [`SalvaContext`] is used.
+    pub kernel_radius: Option<Real>,
+    /// The value of the normalized density field (1.0 inside the fluid, 0.0 outside) at the
+    /// surface of the fluid.
+    pub iso_level: Real,
+    /// The number of Laplacian smoothing passes applied to the mesh vertices.
+    pub smoothing_iterations: usize,
+}
+
+impl Default for FluidSurface3d {
+    fn default() -> Self {
+        Self {
+            cell_size: None,
+            kernel_radius: None,
+            iso_level: 0.5,
+            smoothing_iterations: 2,
+        }
+    }
+}
+
+/// The surface mesh of a fluid built from its [`FluidSurface3d`].
+#[derive(Component, Clone, Debug, Default)]
+pub struct FluidSurfaceMesh {
+    pub mesh: Option<Handle<Mesh>>,
+}
+
+/// Samples the normalized density field of the given particles on a sparse grid.
+///
+/// The field is `sum_j V_j W(|x - x_j|, h)`, which is close to 1.0 inside the fluid. Only the
+/// vertices within the kernel radius of a particle are stored.
+pub fn density_field_3d(
+    positions: &[Vect],
+    particle_volume: Real,
+    kernel_radius: Real,
+    cell_size: Real,
+) -> SparseScalarGrid3d {
+    let mut field = SparseScalarGrid3d::new(cell_size);
+
+    for pos in positions {
+        let mins = ((*pos - Vect::splat(kernel_radius)) / cell_size).floor().as_ivec3();
+        let maxs = ((*pos + Vect::splat(kernel_radius)) / cell_size).ceil().as_ivec3();
+        for x in mins.x..=maxs.x {
+            for y in mins.y..=maxs.y {
+                for z in mins.z..=maxs.z {
+                    let vertex = IVec3::new(x, y, z);
+                    let weight = CubicSplineKernel::scalar_apply(
+                        field.vertex(vertex).distance(*pos),
+                        kernel_radius,
+                    );
+                    if weight > 0.0 {
+                        *field.values.entry(vertex).or_insert(0.0) += particle_volume * weight;
+                    }
+                }
+            }
+        }
+    }
+
+    field
+}
+
+/// The system that rebuilds the [`FluidSurfaceMesh`] of fluids whose particles or transform
+/// changed.
+pub fn update_fluid_surfaces_3d(
+    mut commands: Commands,
+    meshes: Option<ResMut<Assets<Mesh>>>,
+    mut fluids: Query<
+        (
+            Entity,
+            &FluidSurface3d,
+            &mut FluidSurfaceMesh,
+            &GlobalTransform,
+            &FluidPositions,
+            &SalvaContextEntityLink,
+        ),
+        Or<(
+            Changed<FluidPositions>,
+            Changed<FluidSurface3d>,
+            Changed<GlobalTransform>,
+        )>,
+    >,
+    salva_contexts: Query<&SalvaContext>,
+) {
+    let Some(mut meshes) = meshes else {
+        return;
+    };
+
+    for (entity, surface, mut surface_mesh, global_transform, positions, link) in
+        fluids.iter_mut()
+    {
+        let Ok(context) = salva_contexts.get(link.0) else {
+            continue;
+        };
+        let particle_radius = context.liquid_world.particle_radius();
+        let kernel_radius = surface.kernel_radius.unwrap_or(context.liquid_world.h());
+        let cell_size = surface.cell_size.unwrap_or(particle_radius);
+
+        let field = density_field_3d(
+            positions,
+            utils::particle_volume(particle_radius),
+            kernel_radius,
+            cell_size,
+        );
+        let mut output = marching_cubes(&field, surface.iso_level);
+        output.smooth(surface.smoothing_iterations);
+
+        // The density field is sampled in world space: move the mesh to the entity's local space.
+        // Normals are transformed by the inverse transpose of that transform.
+        let world_to_local = global_transform.affine().inverse();
+        let normal_matrix = world_to_local.matrix3.inverse().transpose();
+        let normals: Vec<[f32; 3]> = output
+            .normals()
+            .iter()
+            .map(|n| (normal_matrix * *n).normalize_or_zero().to_array())
+            .collect();
+        let positions: Vec<[f32; 3]> = output
+            .vertices
+            .iter()
+            .map(|v| world_to_local.transform_point3(*v).to_array())
+            .collect();
+        let mesh = Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::default())
+            .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+            .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
+            .with_inserted_indices(Indices::U32(output.indices));
+
+        match surface_mesh.mesh.as_ref().and_then(|handle| meshes.get_mut(handle)) {
+            Some(existing) => *existing = mesh,
+            None => {
+                let handle = meshes.add(mesh);
+                commands.entity(entity).insert(Mesh3d(handle.clone()));
+                surface_mesh.mesh = Some(handle);
+            }
+        }
+    }
+}
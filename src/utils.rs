@@ -1,5 +1,5 @@
+use std::collections::HashMap;
 use salva::math::Real;
-#[cfg(feature = "dim3")]
 use crate::math::Vect;
 
 #[cfg(feature = "dim3")]
@@ -33,3 +33,152 @@ pub fn particle_volume(particle_radius: Real) -> Real {
     particle_volume
 }
 
+
+#[cfg(feature = "dim2")]
+type GridKey = bevy::math::IVec2;
+#[cfg(feature = "dim3")]
+type GridKey = bevy::math::IVec3;
+
+/// A uniform hash grid over particle positions.
+///
+/// Used to find the particles around a point without scanning every fluid of a
+/// [`SalvaContext`](crate::plugin::SalvaContext). The cell size is typically the SPH kernel radius.
+pub struct ParticleGrid<T> {
+    cell_size: Real,
+    cells: HashMap<GridKey, Vec<(Vect, T)>>,
+}
+
+impl<T> ParticleGrid<T> {
+    pub fn new(cell_size: Real) -> Self {
+        Self {
+            cell_size,
+            cells: HashMap::default(),
+        }
+    }
+
+    pub fn cell_size(&self) -> Real {
+        self.cell_size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    pub fn insert(&mut self, point: Vect, value: T) {
+        let key = self.key(point);
+        self.cells.entry(key).or_default().push((point, value));
+    }
+
+    /// Iterates over every entry of the grid.
+    pub fn iter(&self) -> impl Iterator<Item = &(Vect, T)> {
+        self.cells.values().flatten()
+    }
+
+    /// Iterates over the entries whose position lies inside the given AABB.
+    pub fn in_aabb(&self, mins: Vect, maxs: Vect) -> impl Iterator<Item = &(Vect, T)> {
+        let (a, b) = (self.key(mins), self.key(maxs));
+        // Large regions (e.g. the AABB of a half-space) have more keys than occupied cells: scan
+        // the occupied cells instead of looking up every key.
+        let key_count = (0..a.to_array().len())
+            .map(|axis| (b[axis] as f64 - a[axis] as f64 + 1.0).max(0.0))
+            .product::<f64>();
+        let scan_cells = key_count > self.cells.len() as f64;
+
+        let looked_up = (!scan_cells)
+            .then(|| self.keys_between(a, b).filter_map(|key| self.cells.get(&key)))
+            .into_iter()
+            .flatten();
+        let scanned = scan_cells
+            .then(|| {
+                self.cells
+                    .iter()
+                    .filter(move |(key, _)| key.cmpge(a).all() && key.cmple(b).all())
+                    .map(|(_, cell)| cell)
+            })
+            .into_iter()
+            .flatten();
+
+        looked_up
+            .chain(scanned)
+            .flatten()
+            .filter(move |(p, _)| p.cmpge(mins).all() && p.cmple(maxs).all())
+    }
+
+    /// Iterates over the entries whose position lies within `radius` of `center`.
+    pub fn in_radius(&self, center: Vect, radius: Real) -> impl Iterator<Item = &(Vect, T)> {
+        let radius_sq = radius * radius;
+        self.in_aabb(center - Vect::splat(radius), center + Vect::splat(radius))
+            .filter(move |(p, _)| p.distance_squared(center) <= radius_sq)
+    }
+
+    #[cfg(feature = "dim2")]
+    fn key(&self, point: Vect) -> GridKey {
+        (point / self.cell_size).floor().as_ivec2()
+    }
+
+    #[cfg(feature = "dim3")]
+    fn key(&self, point: Vect) -> GridKey {
+        (point / self.cell_size).floor().as_ivec3()
+    }
+
+    #[cfg(feature = "dim2")]
+    fn keys_between(&self, a: GridKey, b: GridKey) -> impl Iterator<Item = GridKey> {
+        (a.x..=b.x).flat_map(move |x| (a.y..=b.y).map(move |y| GridKey::new(x, y)))
+    }
+
+    #[cfg(feature = "dim3")]
+    fn keys_between(&self, a: GridKey, b: GridKey) -> impl Iterator<Item = GridKey> {
+        (a.x..=b.x).flat_map(move |x| {
+            (a.y..=b.y).flat_map(move |y| (a.z..=b.z).map(move |z| GridKey::new(x, y, z)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid() -> ParticleGrid<usize> {
+        let mut grid = ParticleGrid::new(1.0);
+        for i in 0..10 {
+            grid.insert(Vect::splat(i as Real * 0.5), i);
+        }
+        grid
+    }
+
+    fn sorted(values: impl Iterator<Item = usize>) -> Vec<usize> {
+        let mut values: Vec<_> = values.collect();
+        values.sort();
+        values
+    }
+
+    #[test]
+    fn in_aabb_finds_entries_inside() {
+        let grid = grid();
+        let found = sorted(grid.in_aabb(Vect::splat(0.9), Vect::splat(2.1)).map(|(_, i)| *i));
+        assert_eq!(found, vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn in_aabb_handles_unbounded_regions() {
+        let grid = grid();
+        let found = sorted(grid.in_aabb(Vect::splat(Real::MIN), Vect::splat(Real::MAX)).map(|(_, i)| *i));
+        assert_eq!(found, (0..10).collect::<Vec<_>>());
+        let found = sorted(grid.in_aabb(Vect::splat(2.0), Vect::splat(Real::INFINITY)).map(|(_, i)| *i));
+        assert_eq!(found, (4..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn in_aabb_of_an_empty_region_is_empty() {
+        let grid = grid();
+        assert_eq!(grid.in_aabb(Vect::splat(1.0), Vect::splat(0.0)).count(), 0);
+    }
+
+    #[test]
+    fn in_radius_filters_by_distance() {
+        let grid = grid();
+        let center = Vect::splat(1.0);
+        let found = sorted(grid.in_radius(center, 0.5 * Vect::ONE.length() + 1e-4).map(|(_, i)| *i));
+        assert_eq!(found, vec![1, 2, 3]);
+    }
+}